@@ -0,0 +1,328 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use wasmtime::{AsContextMut, Engine, Trap};
+
+use crate::{shutdown::is_shutdown_requested, state::AppState};
+
+// Resolution of the timeouts, the epoch of the engine is incremented this often
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Limits of the time the guest spends running its own code, None means unlimited.
+/// Fuel is consumed by executing wasm instructions, timeouts are wall-clock time
+/// checked while guest code runs. Host functions waiting for samples don't wait past
+/// the timeout of the run, they return -ECANCELED and the guest stops as it resumes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuLimits {
+    // Fuel of the whole run, including the callbacks. There's no fuel per callback,
+    // since wasmtime can't set fuel aside and give it back indefinitely.
+    pub max_fuel: Option<u64>,
+    // Time of the whole `_start`
+    pub timeout: Option<Duration>,
+    // Time of each call of a callback from a host function
+    pub callback_timeout: Option<Duration>,
+}
+
+impl CpuLimits {
+    pub(crate) fn uses_fuel(&self) -> bool {
+        self.max_fuel.is_some()
+    }
+    pub(crate) fn uses_epoch(&self) -> bool {
+        self.timeout.is_some() || self.callback_timeout.is_some()
+    }
+}
+
+/// Error of a run stopped by one of the `CpuLimits`. Everything created by the guest
+/// is still released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeadlineExceeded {
+    RunFuel,
+    RunTimeout,
+    CallbackTimeout,
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeadlineExceeded::RunFuel => "The guest ran out of fuel",
+            DeadlineExceeded::RunTimeout => "The guest exceeded its timeout",
+            DeadlineExceeded::CallbackTimeout => "A callback of the guest exceeded its timeout",
+        })
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Deadlines of the running guest
+#[derive(Debug, Default)]
+pub struct Deadlines {
+    pub limits: CpuLimits,
+    run_deadline: Option<Instant>,
+    callback_deadline: Option<Instant>,
+    // Kept once set, so the guest can't go on after one of its callbacks is stopped
+    exceeded: Option<DeadlineExceeded>,
+}
+
+impl Deadlines {
+    /// Start the timeout of the whole run
+    pub fn start_run(&mut self) {
+        self.run_deadline = self.limits.timeout.map(|v| Instant::now() + v);
+    }
+    pub fn exceeded(&self) -> Option<DeadlineExceeded> {
+        self.exceeded
+    }
+    pub fn run_deadline(&self) -> RunDeadline {
+        RunDeadline(self.run_deadline)
+    }
+    /// Epoch deadline callback of the store, traps once a deadline has passed or a
+    /// shutdown is requested
+    pub fn check(&mut self) -> anyhow::Result<u64> {
+        if is_shutdown_requested() {
+            return Err(Trap::Interrupt.into());
+        }
+        if let Some(exceeded) = self.exceeded {
+            return Err(exceeded.into());
+        }
+        let now = Instant::now();
+        if self.run_deadline.is_some_and(|v| now >= v) {
+            self.exceeded = Some(DeadlineExceeded::RunTimeout);
+        } else if self.callback_deadline.is_some_and(|v| now >= v) {
+            self.exceeded = Some(DeadlineExceeded::CallbackTimeout);
+        }
+        match self.exceeded {
+            Some(exceeded) => Err(exceeded.into()),
+            None => Ok(1),
+        }
+    }
+    /// Map the result of the run to `DeadlineExceeded` if it was stopped by a limit.
+    /// Other errors are kept as the cause.
+    pub fn map_result<T>(&self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        let exceeded = match (&result, self.exceeded) {
+            (Err(err), _) if err.is::<DeadlineExceeded>() => return result,
+            (_, Some(exceeded)) => exceeded,
+            (Err(err), None) if err.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) => {
+                DeadlineExceeded::RunFuel
+            }
+            _ => return result,
+        };
+        match result {
+            Err(err) => Err(err.context(exceeded)),
+            Ok(_) => Err(exceeded.into()),
+        }
+    }
+}
+
+/// The deadline of the whole run, which bounds the waits of host functions since the
+/// epoch can't interrupt them
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunDeadline(Option<Instant>);
+
+impl RunDeadline {
+    /// Cut a wait of up to `timeout`, or forever if None, to the time left
+    pub fn wait_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let left = match self.0 {
+            Some(v) => v.saturating_duration_since(Instant::now()),
+            None => return timeout,
+        };
+        Some(timeout.map_or(left, |v| v.min(left)))
+    }
+    /// Same as `wait_timeout` in milliseconds, negative timeouts wait forever
+    pub fn wait_timeout_ms(&self, timeout_ms: i32) -> i32 {
+        let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+        match self.wait_timeout(timeout) {
+            // Rounded up, so the deadline has passed once the wait times out
+            Some(v) => v.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        }
+    }
+    pub fn passed(&self) -> bool {
+        self.0.is_some_and(|v| Instant::now() >= v)
+    }
+}
+
+/// State saved by `enter_callback` and restored by `leave_callback`
+pub struct CallbackScope {
+    previous_deadline: Option<Instant>,
+}
+
+/// Apply the callback limits before calling guest code from a host function.
+/// Fails without calling anything if the guest already exceeded a limit.
+pub fn enter_callback(
+    mut store: impl AsContextMut<Data = AppState>,
+) -> anyhow::Result<CallbackScope> {
+    let mut store = store.as_context_mut();
+    if let Some(exceeded) = store.data().deadlines.exceeded {
+        return Err(exceeded.into());
+    }
+    let deadlines = &mut store.data_mut().deadlines;
+    let previous_deadline = deadlines.callback_deadline;
+    if let Some(timeout) = deadlines.limits.callback_timeout {
+        let deadline = Instant::now() + timeout;
+        // Callbacks called by callbacks are bounded by the outer deadline too
+        deadlines.callback_deadline = Some(previous_deadline.map_or(deadline, |v| v.min(deadline)));
+    }
+    Ok(CallbackScope { previous_deadline })
+}
+
+/// Restore the limits of the run after a callback returned `result`. A callback
+/// out of fuel leaves the run out of fuel, so the guest traps as soon as it resumes.
+pub fn leave_callback<T>(
+    mut store: impl AsContextMut<Data = AppState>,
+    scope: CallbackScope,
+    result: anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut store = store.as_context_mut();
+    let deadlines = &mut store.data_mut().deadlines;
+    deadlines.callback_deadline = scope.previous_deadline;
+    if let Err(err) = &result {
+        if err.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) && deadlines.exceeded.is_none() {
+            deadlines.exceeded = Some(DeadlineExceeded::RunFuel);
+        }
+    }
+    result
+}
+
+/// Call guest code from a host function within the callback limits
+pub fn call_callback<S, R>(
+    store: &mut S,
+    call: impl FnOnce(&mut S) -> anyhow::Result<R>,
+) -> anyhow::Result<R>
+where
+    S: AsContextMut<Data = AppState>,
+{
+    let scope = enter_callback(&mut *store)?;
+    let result = call(&mut *store);
+    leave_callback(store, scope, result)
+}
+
+/// Thread incrementing the epoch of an engine every `EPOCH_TICK`, so the epoch
+/// deadline callback checks the timeouts. Stopped on drop.
+pub struct EpochTicker {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EpochTicker {
+    pub fn start(engine: &Engine) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            let engine = engine.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(EPOCH_TICK);
+                    engine.increment_epoch();
+                }
+            })
+        };
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exceeded_by(result: anyhow::Result<()>) -> Option<DeadlineExceeded> {
+        result
+            .unwrap_err()
+            .downcast_ref::<DeadlineExceeded>()
+            .copied()
+    }
+
+    #[test]
+    fn results_within_the_limits_are_kept() {
+        let deadlines = Deadlines::default();
+        assert_eq!(deadlines.map_result(Ok(3)).unwrap(), 3);
+        let err = deadlines
+            .map_result::<()>(Err(Trap::UnreachableCodeReached.into()))
+            .unwrap_err();
+        assert!(err.downcast_ref::<DeadlineExceeded>().is_none());
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::UnreachableCodeReached)
+        );
+    }
+
+    #[test]
+    fn out_of_fuel_is_mapped_to_run_fuel() {
+        let deadlines = Deadlines::default();
+        let result = deadlines.map_result(Err(Trap::OutOfFuel.into()));
+        assert_eq!(exceeded_by(result), Some(DeadlineExceeded::RunFuel));
+    }
+
+    #[test]
+    fn exceeded_deadline_is_reported_with_the_cause() {
+        let deadlines = Deadlines {
+            exceeded: Some(DeadlineExceeded::CallbackTimeout),
+            ..Default::default()
+        };
+        let err = deadlines
+            .map_result::<()>(Err(Trap::Interrupt.into()))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DeadlineExceeded>(),
+            Some(&DeadlineExceeded::CallbackTimeout)
+        );
+        assert_eq!(err.downcast_ref::<Trap>(), Some(&Trap::Interrupt));
+        // The guest may catch the error of a callback and return normally
+        let result = deadlines.map_result(Ok(()));
+        assert_eq!(exceeded_by(result), Some(DeadlineExceeded::CallbackTimeout));
+    }
+
+    #[test]
+    fn waits_are_cut_to_the_run_deadline() {
+        let unlimited = Deadlines::default().run_deadline();
+        assert_eq!(unlimited.wait_timeout_ms(-1), -1);
+        assert_eq!(unlimited.wait_timeout_ms(500), 500);
+        assert!(!unlimited.passed());
+        let mut deadlines = Deadlines {
+            limits: CpuLimits {
+                timeout: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        deadlines.start_run();
+        let run_deadline = deadlines.run_deadline();
+        let forever = run_deadline.wait_timeout_ms(-1);
+        assert!(forever > 59_000 && forever <= 60_000);
+        assert_eq!(run_deadline.wait_timeout_ms(500), 500);
+        assert!(!run_deadline.passed());
+        deadlines.limits.timeout = Some(Duration::ZERO);
+        deadlines.start_run();
+        let run_deadline = deadlines.run_deadline();
+        assert_eq!(run_deadline.wait_timeout_ms(-1), 0);
+        assert_eq!(run_deadline.wait_timeout(None), Some(Duration::ZERO));
+        assert!(run_deadline.passed());
+    }
+
+    #[test]
+    fn deadline_exceeded_errors_are_kept() {
+        let deadlines = Deadlines {
+            exceeded: Some(DeadlineExceeded::RunFuel),
+            ..Default::default()
+        };
+        let result = deadlines.map_result(Err(DeadlineExceeded::RunTimeout.into()));
+        assert_eq!(exceeded_by(result), Some(DeadlineExceeded::RunTimeout));
+    }
+}
@@ -0,0 +1,644 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    os::fd::{AsRawFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error};
+use tokio::io::{unix::AsyncFd, Interest};
+use wasmtime::{Func, Val};
+
+use crate::{
+    deadline::{enter_callback, leave_callback},
+    ensure_enough_memory, ensure_program_mut_by_state,
+    func::{ECANCELED, EINTR, EINVAL},
+    state::{CallerType, PollWrapper},
+    utils::CallerUtils,
+};
+
+use super::{
+    poll::{
+        allocated_memory, batch_record_size, find_allocator, find_callback, find_lost_callback,
+        legacy_poll_error, lost_callback_params, sample_callback_code, sample_callback_params,
+        sample_callback_result, sample_size, write_batch_record, BpfBuffer, CallbackKind,
+        MissingCallback, PerfBufferOptions, SampleSink, WasmBufferStats, BUFFER_FLAGS_ALL,
+        BUFFER_FLAG_ALLOC_SAMPLES, STOP_POLLING,
+    },
+    BpfObjectType, WasmPointer,
+};
+
+// Bytes of samples queued for a map until the guest polls it
+const MAX_QUEUED_SIZE: usize = 4 << 20;
+
+/// A ring buffer or perf buffer consumed into a host queue, so samples can be
+/// delivered to the guest with async calls outside of the libbpf callbacks
+pub struct AsyncBuffer {
+    // Registered with the runtime once, dropped before the buffer owning the epoll fd
+    async_fd: Arc<AsyncFd<BorrowedEpollFd>>,
+    buffer: BpfBuffer,
+    // Boxed since libbpf holds a pointer to it
+    samples: Box<QueuedSamples>,
+    // Zero if no lost callback is set
+    lost_callback_index: u32,
+    // BUFFER_FLAG_* set by `wasm_bpf_buffer_set_flags_async`
+    flags: u32,
+}
+
+impl AsyncBuffer {
+    /// Open a buffer of the map `map_fd`, errors are returned as negative error codes
+    pub fn new(map_fd: i32, perf_options: PerfBufferOptions) -> Result<Self, i32> {
+        let mut samples = Box::new(QueuedSamples::new(MAX_QUEUED_SIZE));
+        let buffer = BpfBuffer::bpf_buffer__open_raw(map_fd, perf_options, &mut *samples)?;
+        let epoll_fd = buffer.bpf_buffer__epoll_fd();
+        let async_fd = match AsyncFd::with_interest(BorrowedEpollFd(epoll_fd), Interest::READABLE) {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                error!("Failed to register epoll fd {}: {}", epoll_fd, e);
+                return Err(-e.raw_os_error().unwrap_or(EINVAL));
+            }
+        };
+        Ok(Self {
+            async_fd,
+            buffer,
+            samples,
+            lost_callback_index: 0,
+            flags: 0,
+        })
+    }
+    pub fn stats(&self) -> WasmBufferStats {
+        self.samples.stats
+    }
+}
+
+/// Consumed samples not delivered yet, up to `max_size` bytes. Samples which don't
+/// fit are reported as lost, unless nothing is queued.
+#[derive(Debug)]
+struct QueuedSamples {
+    // As (cpu, data)
+    samples: VecDeque<(i32, Vec<u8>)>,
+    size: usize,
+    max_size: usize,
+    // Samples lost by the buffer or dropped from the queue and not reported yet, by CPU
+    lost: BTreeMap<i32, u64>,
+    stats: WasmBufferStats,
+}
+
+impl QueuedSamples {
+    fn new(max_size: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            size: 0,
+            max_size,
+            lost: BTreeMap::new(),
+            stats: Default::default(),
+        }
+    }
+    fn pop(&mut self) -> Option<(i32, Vec<u8>)> {
+        let sample = self.samples.pop_front()?;
+        self.size -= sample.1.len();
+        Some(sample)
+    }
+    /// Put back a sample taken by `pop`, so it's delivered first
+    fn push_front(&mut self, cpu: i32, data: Vec<u8>) {
+        self.size += data.len();
+        self.samples.push_front((cpu, data));
+    }
+    /// Number of samples and lost sample reports to deliver
+    fn len(&self) -> usize {
+        self.samples.len() + self.lost.len()
+    }
+}
+
+impl SampleSink for QueuedSamples {
+    fn push(&mut self, cpu: i32, data: &[u8]) {
+        self.stats.samples += 1;
+        if !self.samples.is_empty() && self.size + data.len() > self.max_size {
+            *self.lost.entry(cpu).or_default() += 1;
+            self.stats.lost_samples += 1;
+            return;
+        }
+        self.size += data.len();
+        self.samples.push_back((cpu, data.to_vec()));
+    }
+    fn lost(&mut self, cpu: i32, cnt: u64) {
+        debug!("{} samples lost on cpu {}", cnt, cpu);
+        *self.lost.entry(cpu).or_default() += cnt;
+        self.stats.lost_samples += cnt;
+    }
+}
+
+fn async_buffer_mut<'a>(
+    caller: &'a mut CallerType,
+    program: BpfObjectType,
+    fd: i32,
+) -> Option<&'a mut AsyncBuffer> {
+    caller
+        .data_mut()
+        .object_map
+        .get_mut(&program)?
+        .async_buffers
+        .get_mut(&fd)
+}
+
+// The epoll fd is owned by the libbpf buffer, which outlives the `AsyncFd`
+struct BorrowedEpollFd(RawFd);
+
+impl AsRawFd for BorrowedEpollFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Open the buffer of map `fd` for the async runner if it's not opened yet, returns
+/// its epoll fd registered with the runtime or a negative error code
+fn ensure_async_buffer_opened(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    fd: i32,
+) -> Result<Arc<AsyncFd<BorrowedEpollFd>>, i32> {
+    let state = caller.data_mut();
+    let perf_options = state.perf_buffer_options;
+    if let Some(buffer) = state
+        .object_map
+        .get(&program)
+        .and_then(|v| v.async_buffers.get(&fd))
+    {
+        return Ok(buffer.async_fd.clone());
+    }
+    state.check_new_consumer(program, fd)?;
+    let object = state.object_map.get_mut(&program).unwrap();
+    let buffer = AsyncBuffer::new(fd, perf_options)?;
+    let async_fd = buffer.async_fd.clone();
+    object.async_buffers.insert(fd, buffer);
+    Ok(async_fd)
+}
+
+/// Move the available samples of map `fd` into its queue, returns the number of
+/// queued samples or a negative error code
+fn consume_async_buffer(caller: &mut CallerType, program: BpfObjectType, fd: i32) -> i32 {
+    let object = ensure_program_mut_by_state!(caller.data_mut(), program);
+    let buffer = object.async_buffers.get_mut(&fd).unwrap();
+    let res = buffer.buffer.bpf_buffer__consume();
+    if res < 0 && res != -EINTR {
+        return res;
+    }
+    return buffer.samples.len() as i32;
+}
+
+/// Wait until the buffer of map `fd` has samples, for up to `timeout_ms` or forever
+/// if it's negative, but not past the timeout of the run. Returns the number of
+/// queued samples or a negative error code, -ECANCELED if the run timed out.
+async fn wait_samples(
+    caller: &mut CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    async_fd: &AsyncFd<BorrowedEpollFd>,
+    timeout_ms: i32,
+) -> i32 {
+    let res = consume_async_buffer(caller, program, fd);
+    if res != 0 || timeout_ms == 0 {
+        return res;
+    }
+    let run_deadline = caller.data().deadlines.run_deadline();
+    let wait = async {
+        loop {
+            let mut guard = match async_fd.readable().await {
+                Ok(v) => v,
+                Err(e) => return -e.raw_os_error().unwrap_or(EINVAL),
+            };
+            let res = consume_async_buffer(caller, program, fd);
+            if res != 0 {
+                return res;
+            }
+            guard.clear_ready();
+        }
+    };
+    let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+    let timeout = match run_deadline.wait_timeout(timeout) {
+        Some(v) => v,
+        None => return wait.await,
+    };
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(res) => res,
+        Err(_) if run_deadline.passed() => {
+            debug!("Waiting for samples stopped by the timeout of the run");
+            -ECANCELED
+        }
+        // No sample is queued if it timed out
+        Err(_) => 0,
+    }
+}
+
+/// Async version of `deadline::call_callback`
+async fn call_callback_async(
+    caller: &mut CallerType<'_>,
+    func: Func,
+    params: &[Val],
+    results: &mut [Val],
+) -> anyhow::Result<()> {
+    let scope = enter_callback(&mut *caller)?;
+    let result = func.call_async(&mut *caller, params, results).await;
+    leave_callback(&mut *caller, scope, result)
+}
+
+async fn call_sample_callback_async(
+    caller: &mut CallerType<'_>,
+    func: Func,
+    params: &[Val],
+) -> anyhow::Result<i32> {
+    let mut results = vec![Val::I32(0); func.ty(&*caller).results().len()];
+    call_callback_async(caller, func, params, &mut results).await?;
+    Ok(sample_callback_code(&results))
+}
+
+/// Async version of `wasm_bpf_buffer_poll` and `wasm_bpf_buffer_poll_with_cpu`,
+/// waiting for samples without blocking the runtime. Samples left when a callback
+/// stops the poll are delivered by the next call. Errors are reported like the sync
+/// versions do.
+pub async fn wasm_bpf_buffer_poll_async(
+    caller: CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+    with_cpu: bool,
+) -> i32 {
+    debug!("bpf buffer poll async");
+    let res = buffer_poll_async(
+        caller,
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        timeout_ms,
+        with_cpu,
+        false,
+        false,
+    )
+    .await;
+    if with_cpu {
+        res.unwrap_or_else(|err| err)
+    } else {
+        res.unwrap_or_else(legacy_poll_error)
+    }
+}
+
+/// Wait for samples of map `fd` and pass them to the callback, see `poll::buffer_poll`.
+/// Errors of the arguments and of opening the buffer are returned as `Err`, the
+/// result of the poll as `Ok`.
+async fn buffer_poll_async(
+    mut caller: CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+    with_cpu: bool,
+    batch: bool,
+    via_wrapper: bool, // Call the callback export instead of `sample_func`
+) -> Result<i32, i32> {
+    if max_size <= 0 {
+        debug!("Invalid size of the buffer: {}", max_size);
+        return Err(-EINVAL);
+    }
+    ensure_enough_memory!(caller, data, max_size, Err(-EINVAL));
+    let async_fd = ensure_async_buffer_opened(&mut caller, program, fd)?;
+    caller.data_mut().poll_stop_code = None;
+    let res = wait_samples(&mut caller, program, fd, &async_fd, timeout_ms).await;
+    if res <= 0 {
+        if res < 0 {
+            debug!("Failed to poll: {}", res);
+        }
+        return Ok(res);
+    }
+    // Objects closed by the callbacks are released once the samples are delivered
+    caller.data_mut().enter_poll();
+    let res = deliver_samples(
+        &mut caller,
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        with_cpu,
+        batch,
+        via_wrapper,
+    )
+    .await;
+    caller.data_mut().leave_poll();
+    if let Some(code) = caller.data().poll_stop_code {
+        return Ok(code);
+    }
+    return Ok(res);
+}
+
+/// Report the lost samples of map `fd` to the lost callback, then pass the queued
+/// samples to the sample callback, or to the batch callback if `batch` is set.
+/// Returns STOP_POLLING if the guest stopped the poll.
+async fn deliver_samples(
+    caller: &mut CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    with_cpu: bool,
+    batch: bool,
+    via_wrapper: bool,
+) -> i32 {
+    let (lost_callback_index, flags) = match async_buffer_mut(caller, program, fd) {
+        Some(v) => (v.lost_callback_index, v.flags),
+        None => return -1,
+    };
+    while let Some((cpu, cnt)) =
+        async_buffer_mut(caller, program, fd).and_then(|v| v.samples.lost.pop_first())
+    {
+        let func = match find_lost_callback(caller, via_wrapper, lost_callback_index) {
+            Some(v) => v,
+            None => continue,
+        };
+        let params = lost_callback_params(ctx, cpu, cnt);
+        if let Err(e) = call_callback_async(caller, func, &params, &mut []).await {
+            if caller.data().deadlines.exceeded().is_some() {
+                return sample_callback_result(caller, Err(e));
+            }
+            error!("Failed to call the lost callback: {}", e);
+        }
+    }
+    let kind = if batch {
+        CallbackKind::Batch
+    } else {
+        CallbackKind::Sample
+    };
+    let func = match find_callback(caller, kind, via_wrapper, sample_func) {
+        Ok(v) => v,
+        Err(MissingCallback::Export) => {
+            caller.data_mut().poll_stop_code = Some(-EINVAL);
+            return STOP_POLLING;
+        }
+        // The samples are kept for a poll with a valid callback
+        Err(MissingCallback::TableEntry) => return -EINVAL,
+    };
+    if batch {
+        return deliver_batches(caller, program, fd, func, ctx, data, max_size as usize).await;
+    }
+    let memory = caller.get_memory().expect("Memory must be exported");
+    while let Some((cpu, sample)) =
+        async_buffer_mut(caller, program, fd).and_then(|v| v.samples.pop())
+    {
+        let mut wasm_buffer = data;
+        let mut available_length = sample.len().min(max_size as usize);
+        if flags & BUFFER_FLAG_ALLOC_SAMPLES != 0 {
+            if let Some(ptr) = alloc_guest_memory_async(caller, sample.len() as u32).await {
+                wasm_buffer = ptr;
+                available_length = sample.len();
+            }
+        }
+        if let Err(e) = memory.write(
+            &mut *caller,
+            wasm_buffer as usize,
+            &sample[..available_length],
+        ) {
+            error!("Failed to write wasm memory: {}", e);
+            return -EINVAL;
+        }
+        let size = sample_size(sample.len(), available_length, flags);
+        let params = sample_callback_params(ctx, with_cpu.then_some(cpu), wasm_buffer, size);
+        let result = call_sample_callback_async(caller, func, &params).await;
+        let res = sample_callback_result(caller, result);
+        if res != 0 {
+            return res;
+        }
+    }
+    return 0;
+}
+
+/// Pack the queued samples of map `fd` into the arena at `data` of `max_size` bytes, and
+/// pass them to the batch callback each time the arena is full and at the end. The
+/// sample which doesn't fit when the guest stops the poll stays queued. Returns
+/// STOP_POLLING if the guest stopped the poll.
+async fn deliver_batches(
+    caller: &mut CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    func: Func,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: usize,
+) -> i32 {
+    let mut offset = 0;
+    let mut count = 0;
+    while let Some((cpu, sample)) =
+        async_buffer_mut(caller, program, fd).and_then(|v| v.samples.pop())
+    {
+        if count > 0 && offset + batch_record_size(sample.len()) > max_size {
+            let res = call_batch_callback_async(caller, func, ctx, data, count).await;
+            offset = 0;
+            count = 0;
+            if res != 0 {
+                if let Some(buffer) = async_buffer_mut(caller, program, fd) {
+                    buffer.samples.push_front(cpu, sample);
+                }
+                return res;
+            }
+        }
+        match write_batch_record(caller, data, offset, max_size, cpu, &sample) {
+            Some(size) => {
+                offset += size;
+                count += 1;
+            }
+            None => return -EINVAL,
+        }
+    }
+    if count > 0 {
+        return call_batch_callback_async(caller, func, ctx, data, count).await;
+    }
+    return 0;
+}
+
+/// Call the batch callback with the `count` records packed in the arena, returns
+/// STOP_POLLING if the guest stopped the poll
+async fn call_batch_callback_async(
+    caller: &mut CallerType<'_>,
+    func: Func,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    count: u32,
+) -> i32 {
+    let params = [
+        Val::I32(ctx as _),
+        Val::I32(data as _),
+        Val::I32(count as _),
+    ];
+    let result = call_sample_callback_async(caller, func, &params).await;
+    sample_callback_result(caller, result)
+}
+
+/// Async version of `poll::alloc_guest_memory`
+async fn alloc_guest_memory_async(caller: &mut CallerType<'_>, size: u32) -> Option<u32> {
+    let func = find_allocator(caller)?;
+    let mut results = [Val::I32(0)];
+    let ptr = call_callback_async(caller, *func.func(), &[Val::I32(size as _)], &mut results)
+        .await
+        .map(|_| results[0].unwrap_i32() as u32);
+    allocated_memory(caller, ptr, size)
+}
+
+/// Async version of `wasm_bpf_buffer_set_flags`, which opens the buffer of the map
+pub fn wasm_bpf_buffer_set_flags_async(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    flags: u32,
+) -> i32 {
+    debug!("bpf buffer set flags async");
+    if flags & !BUFFER_FLAGS_ALL != 0 {
+        debug!("Unknown buffer flags: {:#x}", flags);
+        return -EINVAL;
+    }
+    if let Err(err) = ensure_async_buffer_opened(&mut caller, program, fd) {
+        return err;
+    }
+    async_buffer_mut(&mut caller, program, fd).unwrap().flags = flags;
+    return 0;
+}
+
+/// Async version of `wasm_bpf_buffer_poll_batch`. Samples left when the callback stops
+/// the poll are delivered by the next call.
+pub async fn wasm_bpf_buffer_poll_batch_async(
+    caller: CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    batch_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!("bpf buffer poll batch async");
+    if (max_size as usize) < batch_record_size(1) {
+        debug!("Arena of {} bytes is too small", max_size);
+        return -EINVAL;
+    }
+    buffer_poll_async(
+        caller,
+        program,
+        fd,
+        batch_func,
+        ctx,
+        data,
+        max_size,
+        timeout_ms,
+        false,
+        true,
+        batch_func == 0,
+    )
+    .await
+    .unwrap_or_else(|err| err)
+}
+
+/// Async version of `wasm_bpf_buffer_set_lost_callback`. Ring buffers are accepted
+/// too, since samples which don't fit in the queue of the map are reported as lost.
+pub fn wasm_bpf_buffer_set_lost_callback_async(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    lost_func: WasmPointer,
+) -> i32 {
+    debug!("bpf buffer set lost callback async");
+    if let Err(err) = ensure_async_buffer_opened(&mut caller, program, fd) {
+        return err;
+    }
+    async_buffer_mut(&mut caller, program, fd)
+        .unwrap()
+        .lost_callback_index = lost_func;
+    return 0;
+}
+
+/// Async version of the poll wrapper, see `wrapper_poll::bpf_buffer_poll_wrapper`
+pub async fn bpf_buffer_poll_wrapper_async(
+    mut caller: CallerType<'_>,
+    program: BpfObjectType,
+    fd: i32,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    let callback_func_name = match caller.data().poll_wrapper {
+        PollWrapper::Disabled => {
+            panic!("Something terrible happened. bpf_buffer_poll_wrapper must be called with poll_wrapper=PollWrapper::Enabled{{}}");
+        }
+        PollWrapper::Enabled {
+            ref callback_function_name,
+            ..
+        } => callback_function_name.clone(),
+    };
+    if caller
+        .get_export(&callback_func_name)
+        .and_then(|v| v.into_func())
+        .is_none()
+    {
+        error!("Callback export named {} not found", callback_func_name);
+        return EINVAL;
+    }
+    buffer_poll_async(
+        caller, program, fd, 0, ctx, data, max_size, timeout_ms, false, false, true,
+    )
+    .await
+    .unwrap_or_else(legacy_poll_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_over_the_limit_are_reported_as_lost() {
+        let mut samples = QueuedSamples::new(8);
+        samples.push(0, &[1; 6]);
+        samples.push(1, &[2; 6]);
+        samples.push(1, &[3; 2]);
+        samples.lost(2, 5);
+        assert_eq!(samples.samples.len(), 2);
+        assert_eq!(samples.size, 8);
+        assert_eq!(samples.lost, BTreeMap::from([(1, 1), (2, 5)]));
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples.stats.samples, 3);
+        assert_eq!(samples.stats.lost_samples, 6);
+        assert_eq!(samples.pop(), Some((0, vec![1; 6])));
+        assert_eq!(samples.size, 2);
+    }
+
+    #[test]
+    fn oversized_sample_is_queued_when_the_queue_is_empty() {
+        let mut samples = QueuedSamples::new(8);
+        samples.push(0, &[0; 32]);
+        assert_eq!(samples.samples.len(), 1);
+        assert!(samples.lost.is_empty());
+    }
+
+    #[test]
+    fn sample_put_back_is_popped_first() {
+        let mut samples = QueuedSamples::new(16);
+        samples.push(0, &[1; 4]);
+        samples.push(1, &[2; 4]);
+        let first = samples.pop().unwrap();
+        assert_eq!(samples.size, 4);
+        samples.push_front(first.0, first.1);
+        assert_eq!(samples.size, 8);
+        assert_eq!(samples.pop(), Some((0, vec![1; 4])));
+        assert_eq!(samples.pop(), Some((1, vec![2; 4])));
+        assert_eq!(samples.size, 0);
+    }
+}
@@ -2,7 +2,11 @@ use std::os::fd::AsRawFd;
 
 use log::debug;
 
-use crate::{ensure_c_str, ensure_program_mut_by_state, state::CallerType};
+use crate::{
+    ensure_c_str, ensure_program_mut_by_state,
+    func::EMFILE,
+    state::{BpfResource, CallerType},
+};
 
 use super::{BpfObjectType, WasmString};
 
@@ -21,6 +25,10 @@ pub fn wasm_attach_bpf_program(
         Some(ensure_c_str!(caller, attach_target))
     };
     let state = caller.data_mut();
+    if !state.can_create(BpfResource::Link) {
+        return -EMFILE;
+    }
+    let can_open_file = state.can_create(BpfResource::OpenedFile);
     let object = ensure_program_mut_by_state!(state, program);
 
     let program = match object.get_object_mut().prog_mut(&name_str) {
@@ -34,6 +42,9 @@ pub fn wasm_attach_bpf_program(
         let section_name = program.section();
         // TODO: support more attach type
         if section_name == "sockops" {
+            if !can_open_file {
+                return -EMFILE;
+            }
             let cgroup_file = match std::fs::OpenOptions::new().read(true).open(&attach_target) {
                 Ok(v) => v,
                 Err(err) => {
@@ -0,0 +1,393 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    ffi::c_void,
+    io::IoSliceMut,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    slice::from_raw_parts,
+    sync::{Arc, Mutex},
+};
+
+use log::debug;
+use wasi_common::{
+    file::{FdFlags, FileCaps, FileType},
+    Error, ErrorExt, WasiFile,
+};
+
+use crate::{
+    deadline::RunDeadline,
+    ensure_program_mut_by_state,
+    func::{ECANCELED, EINTR, EINVAL},
+    shutdown::is_shutdown_requested,
+    state::CallerType,
+};
+
+use super::{
+    poll::{
+        BpfBuffer, PerfBufferOptions, SampleSink, WasmBatchRecord, WasmBufferStats, STOP_POLLING,
+    },
+    BpfObjectType,
+};
+
+// Bytes of framed samples kept for a buffer file until the guest reads them
+const MAX_PENDING_SIZE: usize = 4 << 20;
+
+/// Samples framed as `WasmBatchRecord`s aligned to 8 bytes. The sink is full once
+/// `max_size` bytes are taken, so the other samples stay in the buffer.
+#[derive(Debug)]
+pub struct FramedSamples {
+    data: Vec<u8>,
+    max_size: usize,
+    stats: WasmBufferStats,
+}
+
+impl FramedSamples {
+    fn new(max_size: usize) -> Self {
+        Self {
+            data: vec![],
+            max_size,
+            stats: Default::default(),
+        }
+    }
+}
+
+impl SampleSink for FramedSamples {
+    fn push(&mut self, cpu: i32, data: &[u8]) {
+        self.stats.samples += 1;
+        let size = (std::mem::size_of::<WasmBatchRecord>() + data.len()).next_multiple_of(8);
+        let record = WasmBatchRecord {
+            len: data.len() as u32,
+            size: data.len() as u32,
+            cpu,
+            reserved: 0,
+        };
+        let start = self.data.len();
+        self.data.extend_from_slice(unsafe {
+            from_raw_parts(
+                &record as *const WasmBatchRecord as *const u8,
+                std::mem::size_of::<WasmBatchRecord>(),
+            )
+        });
+        self.data.extend_from_slice(data);
+        self.data.resize(start + size, 0);
+    }
+    fn lost(&mut self, cpu: i32, cnt: u64) {
+        debug!("{} samples lost on cpu {}", cnt, cpu);
+        self.stats.lost_samples += cnt;
+    }
+    fn is_full(&self) -> bool {
+        self.data.len() >= self.max_size
+    }
+}
+
+/// The buffer read by a buffer file and the samples consumed from it, owned by the
+/// object of the map so they are released when the object is closed
+struct FileBuffer {
+    // Dropped before the samples it points to
+    buffer: BpfBuffer,
+    samples: Box<FramedSamples>,
+    // `BufferFile::pending_event`, set when the object is closed
+    pending_event: Arc<OwnedFd>,
+}
+
+/// Buffers of the buffer files reading maps of an object, indexed by the map fd
+#[derive(Clone, Default)]
+pub struct BufferFiles(Arc<Mutex<HashMap<i32, FileBuffer>>>);
+
+// SAFETY: `WasiFile` requires Send and Sync. The raw pointers of `BpfBuffer` are
+// libbpf buffers, which have no thread affinity, and the sample sink they point to
+// is boxed next to them. Both are only used through the mutex, which serializes
+// consuming and polling the buffers, and freed when the entry is removed.
+unsafe impl Send for BufferFiles {}
+unsafe impl Sync for BufferFiles {}
+
+impl BufferFiles {
+    pub fn contains(&self, fd: i32) -> bool {
+        self.0.lock().unwrap().contains_key(&fd)
+    }
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+    pub fn stats(&self, fd: i32) -> Option<WasmBufferStats> {
+        self.0.lock().unwrap().get(&fd).map(|v| v.samples.stats)
+    }
+    /// Release the buffers when their object is closed. Their files read the end of
+    /// file once the samples read before are consumed.
+    pub fn close_all(&self) {
+        for (_, file_buffer) in self.0.lock().unwrap().drain() {
+            set_event(file_buffer.pending_event.as_raw_fd(), true);
+        }
+    }
+}
+
+/// Make the eventfd `fd` readable or not
+fn set_event(fd: i32, readable: bool) {
+    let mut value = 1u64;
+    let value_ptr = &mut value as *mut u64 as *mut c_void;
+    let size = std::mem::size_of::<u64>();
+    unsafe {
+        if readable {
+            libc::write(fd, value_ptr, size);
+        } else {
+            // Reset the counter, fails with EAGAIN if it's already zero
+            libc::read(fd, value_ptr, size);
+        }
+    }
+}
+
+/// A ring buffer or perf buffer read through a WASI file descriptor. Reading it
+/// returns samples framed as `WasmBatchRecord`s, which may be split across reads.
+/// Once the object of the map is closed, it reads the end of file.
+pub struct BufferFile {
+    // Shared with the object owning the map
+    buffers: BufferFiles,
+    map_fd: i32,
+    // Framed samples taken from the buffer but not read by the guest yet
+    pending: Vec<u8>,
+    nonblocking: bool,
+    // Readable while there are pending samples, which the buffer can't report
+    pending_event: Arc<OwnedFd>,
+    // Contains the epoll fd of the buffer and `pending_event`, polled by `poll_oneoff`
+    epoll: OwnedFd,
+    // Blocking reads don't wait past it
+    run_deadline: RunDeadline,
+}
+
+fn last_os_error() -> i32 {
+    -std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(EINVAL)
+}
+
+impl BufferFile {
+    /// Open a buffer of the map `map_fd`, which is added to `buffers` until the file
+    /// is dropped. Errors are returned as negative error codes.
+    pub fn new(
+        map_fd: i32,
+        perf_options: PerfBufferOptions,
+        buffers: BufferFiles,
+        run_deadline: RunDeadline,
+    ) -> Result<Self, i32> {
+        let mut samples = Box::new(FramedSamples::new(MAX_PENDING_SIZE));
+        let buffer = BpfBuffer::bpf_buffer__open_raw(map_fd, perf_options, &mut *samples)?;
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd < 0 {
+            return Err(last_os_error());
+        }
+        let pending_event = Arc::new(unsafe { OwnedFd::from_raw_fd(event_fd) });
+        let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll_fd < 0 {
+            return Err(last_os_error());
+        }
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll_fd) };
+        for fd in [buffer.bpf_buffer__epoll_fd(), pending_event.as_raw_fd()] {
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: fd as u64,
+            };
+            if unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+                return Err(last_os_error());
+            }
+        }
+        buffers.0.lock().unwrap().insert(
+            map_fd,
+            FileBuffer {
+                buffer,
+                samples,
+                pending_event: pending_event.clone(),
+            },
+        );
+        Ok(Self {
+            buffers,
+            map_fd,
+            pending: vec![],
+            nonblocking: false,
+            pending_event,
+            epoll,
+            run_deadline,
+        })
+    }
+    /// Take the available samples once the pending ones are read, waiting for some if
+    /// `block` is set until the timeout of the run passes. Samples are taken until
+    /// `MAX_PENDING_SIZE` bytes are reached, the others stay in the buffer. Returns
+    /// false if the object of the map is closed.
+    fn fill(&mut self, block: bool) -> Result<bool, Error> {
+        let mut buffers = self.buffers.0.lock().unwrap();
+        let file_buffer = match buffers.get_mut(&self.map_fd) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let mut res = file_buffer.buffer.bpf_buffer__consume();
+        while res >= 0 && block && file_buffer.samples.data.is_empty() {
+            if self.run_deadline.passed() {
+                debug!("Waiting for samples stopped by the timeout of the run");
+                res = -ECANCELED;
+                break;
+            }
+            res = file_buffer
+                .buffer
+                .bpf_buffer__poll(self.run_deadline.wait_timeout_ms(-1));
+            if res == -EINTR && !is_shutdown_requested() {
+                res = 0;
+            }
+        }
+        if res == STOP_POLLING && file_buffer.samples.is_full() {
+            // Stopped by the sink, not an error
+            res = 0;
+        }
+        self.pending.append(&mut file_buffer.samples.data);
+        if res < 0 {
+            debug!("Failed to consume buffer: {}", res);
+            return Err(std::io::Error::from_raw_os_error(-res).into());
+        }
+        Ok(true)
+    }
+    /// Make `pending_event` readable if and only if there are pending samples or the
+    /// end of file is reached
+    fn update_pending_event(&self) {
+        let readable = !self.pending.is_empty() || !self.buffers.contains(self.map_fd);
+        set_event(self.pending_event.as_raw_fd(), readable);
+    }
+}
+
+impl Drop for BufferFile {
+    fn drop(&mut self) {
+        self.buffers.0.lock().unwrap().remove(&self.map_fd);
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiFile for BufferFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::Pipe)
+    }
+    fn pollable(&self) -> Option<BorrowedFd<'_>> {
+        Some(unsafe { BorrowedFd::borrow_raw(self.epoll.as_raw_fd()) })
+    }
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(if self.nonblocking {
+            FdFlags::NONBLOCK
+        } else {
+            FdFlags::empty()
+        })
+    }
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        if !(flags - FdFlags::NONBLOCK).is_empty() {
+            return Err(Error::invalid_argument().context("only NONBLOCK can be set"));
+        }
+        self.nonblocking = flags.contains(FdFlags::NONBLOCK);
+        Ok(())
+    }
+    async fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        if self.pending.is_empty() && !self.fill(!self.nonblocking)? {
+            // The object of the map is closed
+            return Ok(0);
+        }
+        if self.pending.is_empty() {
+            return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+        }
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let len = buf.len().min(self.pending.len() - read);
+            buf[..len].copy_from_slice(&self.pending[read..read + len]);
+            read += len;
+        }
+        self.pending.drain(..read);
+        self.update_pending_event();
+        Ok(read as u64)
+    }
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.pending.len() as u64)
+    }
+}
+
+/// Open a WASI file descriptor reading the ring buffer or perf buffer map `fd` of
+/// `program`, which is readable by `poll_oneoff` when samples are pending. Reading it
+/// returns samples framed as `WasmBatchRecord`s, and the end of file once the object is
+/// closed. Samples are taken from the buffer up to `MAX_PENDING_SIZE` bytes ahead of
+/// the reads, the others stay in it. Returns the file descriptor, or a negative error code.
+/// The map can't be consumed by other means in the meantime.
+pub fn wasm_bpf_buffer_open_fd(mut caller: CallerType, program: BpfObjectType, fd: i32) -> i32 {
+    debug!("bpf buffer open fd");
+    let state = caller.data_mut();
+    if let Err(err) = state.check_new_consumer(program, fd) {
+        return err;
+    }
+    let perf_options = state.perf_buffer_options;
+    let run_deadline = state.deadlines.run_deadline();
+    let object = ensure_program_mut_by_state!(state, program);
+    let file = match BufferFile::new(fd, perf_options, object.buffer_files.clone(), run_deadline) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Failed to open buffer file for map fd {}: {}", fd, err);
+            return err;
+        }
+    };
+    match state.wasi.push_file(
+        Box::new(file),
+        FileCaps::READ | FileCaps::POLL_READWRITE | FileCaps::FDSTAT_SET_FLAGS,
+    ) {
+        Ok(v) => v as i32,
+        Err(err) => {
+            debug!("Failed to push buffer file: {}", err);
+            -EINVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORD_SIZE: usize = std::mem::size_of::<WasmBatchRecord>();
+
+    fn record_at(data: &[u8], offset: usize) -> WasmBatchRecord {
+        unsafe { std::ptr::read_unaligned(data[offset..].as_ptr() as *const WasmBatchRecord) }
+    }
+
+    #[test]
+    fn samples_are_framed_and_aligned() {
+        let mut samples = FramedSamples::new(MAX_PENDING_SIZE);
+        samples.push(1, b"abc");
+        samples.push(2, b"12345678");
+        let first = record_at(&samples.data, 0);
+        assert_eq!((first.len, first.size, first.cpu), (3, 3, 1));
+        assert_eq!(&samples.data[RECORD_SIZE..RECORD_SIZE + 3], b"abc");
+        let second_offset = (RECORD_SIZE + 3).next_multiple_of(8);
+        let second = record_at(&samples.data, second_offset);
+        assert_eq!((second.len, second.size, second.cpu), (8, 8, 2));
+        assert_eq!(
+            &samples.data[second_offset + RECORD_SIZE..second_offset + RECORD_SIZE + 8],
+            b"12345678"
+        );
+        assert_eq!(samples.data.len(), second_offset + RECORD_SIZE + 8);
+        assert_eq!(samples.data.len() % 8, 0);
+        assert_eq!(samples.stats.samples, 2);
+        assert_eq!(samples.stats.lost_samples, 0);
+    }
+
+    #[test]
+    fn samples_are_full_at_the_limit() {
+        let mut samples = FramedSamples::new(2 * (RECORD_SIZE + 8));
+        samples.push(0, b"first");
+        assert!(!samples.is_full());
+        samples.push(0, b"second");
+        assert!(samples.is_full());
+        assert_eq!(samples.stats.samples, 2);
+        assert_eq!(samples.stats.lost_samples, 0);
+        samples.lost(0, 3);
+        assert_eq!(samples.stats.lost_samples, 3);
+    }
+
+    #[test]
+    fn oversized_sample_is_kept() {
+        let mut samples = FramedSamples::new(8);
+        samples.push(0, &[0xaa; 32]);
+        assert_eq!(samples.data.len(), RECORD_SIZE + 32);
+        assert!(samples.is_full());
+        assert_eq!(samples.stats.lost_samples, 0);
+    }
+}
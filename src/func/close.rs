@@ -1,22 +1,16 @@
-use std::collections::hash_map::Entry;
-
 use log::debug;
 
 use crate::state::CallerType;
 
 use super::BpfObjectType;
 
+/// Close the object `program`. Called from a sample callback, the object is released
+/// once the poll returns.
 pub fn wasm_close_bpf_object(mut caller: CallerType, program: BpfObjectType) -> i32 {
     debug!("Close bpf object: {}", program);
-    let state = caller.data_mut();
-    match state.object_map.entry(program) {
-        Entry::Occupied(v) => {
-            v.remove();
-            return 0;
-        }
-        Entry::Vacant(_) => {
-            debug!("Invalid bpf object id: {}", program);
-            return -1;
-        }
-    };
+    if !caller.data_mut().close_object(program) {
+        debug!("Invalid bpf object id: {}", program);
+        return -1;
+    }
+    return 0;
 }
@@ -0,0 +1,139 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    io::Read,
+    os::fd::{AsRawFd, FromRawFd},
+};
+
+use libbpf_rs::{
+    libbpf_sys::{
+        bpf_iter_attach_opts, bpf_iter_create, bpf_iter_link_info,
+        bpf_object__find_program_by_name, bpf_program__attach_iter,
+    },
+    Link,
+};
+use log::debug;
+
+use crate::{
+    ensure_c_str, ensure_enough_memory, ensure_program_mut_by_state,
+    func::{EINVAL, EMFILE, ENOENT},
+    state::{BpfResource, CallerType},
+    utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer, WasmString};
+
+/// An iterator created from an `iter/...` program.
+/// The link is kept alive as long as the iterator is open.
+pub struct BpfIter {
+    pub file: File,
+    _link: Link,
+}
+
+/// Attach the iter program named `name` and create an iterator from it.
+/// `map_fd` is used by `iter/bpf_map_elem` like programs, pass a negative
+/// value for programs that don't iterate over a map. Returns the fd of the iterator,
+/// or a negative error code.
+pub fn wasm_bpf_iter_create(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    map_fd: i32,
+) -> i32 {
+    debug!("iter create");
+    let prog_name = ensure_c_str!(caller, name);
+    let state = caller.data_mut();
+    if map_fd >= 0 && !state.is_known_map_fd(map_fd) {
+        debug!("No map with fd `{}` found", map_fd);
+        return -ENOENT;
+    }
+    if !state.can_create(BpfResource::Link) || !state.can_create(BpfResource::OpenedFile) {
+        return -EMFILE;
+    }
+    let object = ensure_program_mut_by_state!(state, program);
+    // Names read from wasm memory never contain interior zeros
+    let prog_name_c = CString::new(prog_name.as_str()).unwrap();
+    let prog_ptr = unsafe {
+        bpf_object__find_program_by_name(object.get_raw_object_ptr(), prog_name_c.as_ptr())
+    };
+    if prog_ptr.is_null() {
+        debug!("No program named `{}` found", prog_name);
+        return -ENOENT;
+    }
+    let mut link_info = bpf_iter_link_info::default();
+    if map_fd >= 0 {
+        link_info.map.map_fd = map_fd as u32;
+    }
+    let opts = bpf_iter_attach_opts {
+        sz: std::mem::size_of::<bpf_iter_attach_opts>() as _,
+        link_info: &mut link_info,
+        link_info_len: std::mem::size_of::<bpf_iter_link_info>() as _,
+        ..Default::default()
+    };
+    let link_ptr = unsafe {
+        bpf_program__attach_iter(prog_ptr, if map_fd >= 0 { &opts } else { std::ptr::null() })
+    };
+    if link_ptr.is_null() {
+        let err = std::io::Error::last_os_error();
+        debug!("Failed to attach iter program `{}`: {}", prog_name, err);
+        return -err.raw_os_error().unwrap_or(EINVAL);
+    }
+    let link = unsafe { Link::from_ptr(link_ptr) };
+    let fd = unsafe { bpf_iter_create(link.fd()) };
+    if fd < 0 {
+        debug!("Failed to create iter: {}", fd);
+        return fd;
+    }
+    let iter = BpfIter {
+        file: unsafe { File::from_raw_fd(fd) },
+        _link: link,
+    };
+    state.iterators.insert(iter.file.as_raw_fd(), iter);
+    return fd;
+}
+
+/// Read at most `buf_len` bytes of output from the iterator.
+/// Returns the number of bytes read, zero means the end of the iteration, or a
+/// negative error code.
+pub fn wasm_bpf_iter_read(
+    mut caller: CallerType,
+    iter_fd: i32,
+    buf: WasmPointer,
+    buf_len: u32,
+) -> i32 {
+    debug!("iter read");
+    if buf_len == 0 {
+        return 0;
+    }
+    ensure_enough_memory!(caller, buf, buf_len, -EINVAL);
+    let memory = caller.get_memory().expect("Expected exported memory!");
+    let (memory, state) = memory.data_and_store_mut(&mut caller);
+    let iter = match state.iterators.get_mut(&iter_fd) {
+        Some(v) => v,
+        None => {
+            debug!("No iterator with fd `{}` found", iter_fd);
+            return -ENOENT;
+        }
+    };
+    match iter
+        .file
+        .read(&mut memory[buf as usize..buf as usize + buf_len as usize])
+    {
+        Ok(v) => v as i32,
+        Err(err) => {
+            debug!("Failed to read iter: {}", err);
+            -err.raw_os_error().unwrap_or(EINVAL)
+        }
+    }
+}
+
+pub fn wasm_bpf_iter_close(mut caller: CallerType, iter_fd: i32) -> i32 {
+    debug!("iter close");
+    match caller.data_mut().iterators.remove(&iter_fd) {
+        Some(_) => 0,
+        None => {
+            debug!("No iterator with fd `{}` found", iter_fd);
+            -ENOENT
+        }
+    }
+}
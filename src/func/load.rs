@@ -2,12 +2,14 @@ use libbpf_rs::ObjectBuilder;
 use log::debug;
 
 use crate::{
-    state::{CallerType, WrapperObject},
+    state::{BpfResource, CallerType, WrapperObject},
     utils::CallerUtils,
 };
 
 use super::WasmPointer;
 
+/// Returns the id of the loaded object, or 0 if it fails to load. Reaching the limit
+/// of objects is a failure too, which is logged as a warning.
 pub fn wasm_load_bpf_object(
     mut caller: CallerType,
     obj_buf: WasmPointer,
@@ -15,7 +17,7 @@ pub fn wasm_load_bpf_object(
 ) -> u64 {
     debug!("Load bpf object caller");
     let memory = caller.get_memory().expect("Expected exported `memory`");
-    let mut buf = vec![0u8];
+    let mut buf = [0u8];
     if let Err(err) = memory.read(
         &mut caller,
         obj_buf as usize + obj_buf_size as usize - 1,
@@ -27,6 +29,9 @@ pub fn wasm_load_bpf_object(
         );
         return 0;
     }
+    if !caller.data().can_create(BpfResource::Object) {
+        return 0;
+    }
     let open_object = match ObjectBuilder::default().open_memory(
         "",
         &memory.data(&mut caller)[obj_buf as usize..(obj_buf + obj_buf_size) as usize],
@@ -44,14 +49,19 @@ pub fn wasm_load_bpf_object(
             return 0;
         }
     };
-    let mut state = caller.data_mut();
+    let state = caller.data_mut();
     let next_id = state.next_object_id;
     state.next_object_id += 1;
     state.object_map.insert(
         next_id,
         WrapperObject {
             object,
-            buffer: None,
+            buffers: vec![],
+            queues: Default::default(),
+            #[cfg(feature = "async")]
+            async_buffers: Default::default(),
+            buffer_files: Default::default(),
+            pending_buffer_flags: Default::default(),
         },
     );
     debug!("Load bpf object done, id={}", next_id);
@@ -0,0 +1,54 @@
+use std::{ffi::CString, ptr::null};
+
+use libbpf_rs::libbpf_sys::{bpf_map_create, bpf_map_create_opts};
+use log::debug;
+
+use crate::{
+    ensure_c_str,
+    state::{BpfResource, CallerType},
+};
+
+use super::{WasmString, EMFILE};
+
+/// Create a map which doesn't belong to any object. It's owned by the runtime
+/// and will be closed when the guest exits, or by `wasm_bpf_map_close`
+pub fn wasm_bpf_map_create(
+    mut caller: CallerType,
+    map_type: u32,
+    name: WasmString, // Allow null pointers
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    flags: u32,
+) -> i32 {
+    debug!("map create");
+    if !caller.data().can_create(BpfResource::HostMap) {
+        return -EMFILE;
+    }
+    let name_str = if name == 0 {
+        None
+    } else {
+        // The string read from wasm memory never contains interior zeros
+        Some(CString::new(ensure_c_str!(caller, name)).unwrap())
+    };
+    let opts = bpf_map_create_opts {
+        sz: std::mem::size_of::<bpf_map_create_opts>() as _,
+        map_flags: flags,
+        ..Default::default()
+    };
+    let fd = unsafe {
+        bpf_map_create(
+            map_type,
+            name_str.as_ref().map(|v| v.as_ptr()).unwrap_or(null()),
+            key_size,
+            value_size,
+            max_entries,
+            &opts,
+        )
+    };
+    if fd < 0 {
+        debug!("Failed to create map: {}", fd);
+        return fd;
+    }
+    return caller.data_mut().register_host_map(fd);
+}
@@ -0,0 +1,167 @@
+use std::{ffi::c_void, os::fd::AsRawFd};
+
+use libbpf_rs::libbpf_sys::{
+    bpf_map_create, bpf_map_create_opts, bpf_map_get_fd_by_id, bpf_map_lookup_elem,
+    bpf_map_update_elem, BPF_MAP_TYPE_ARRAY_OF_MAPS, BPF_MAP_TYPE_HASH_OF_MAPS,
+};
+use log::debug;
+
+use crate::{
+    ensure_enough_memory,
+    state::{query_map_info, AppState, BpfResource, CallerType},
+    utils::CallerUtils,
+};
+
+use super::{WasmPointer, EINVAL, EMFILE, ENOENT};
+
+/// Returns the key size of the outer map, -ENOENT if `fd` isn't a known map or
+/// -EINVAL if it isn't a map-in-map
+fn outer_map_key_size(state: &AppState, fd: i32) -> Result<usize, i32> {
+    if !state.is_known_map_fd(fd) {
+        debug!("No map with fd `{}` found", fd);
+        return Err(-ENOENT);
+    }
+    let info = match query_map_info(fd) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Failed to query info of map {}: {}", fd, err);
+            return Err(err);
+        }
+    };
+    if info.type_ != BPF_MAP_TYPE_ARRAY_OF_MAPS && info.type_ != BPF_MAP_TYPE_HASH_OF_MAPS {
+        debug!("Map {} is not a map-in-map, type={}", fd, info.type_);
+        return Err(-EINVAL);
+    }
+    Ok(info.key_size as usize)
+}
+
+pub fn wasm_bpf_map_create_inner(
+    mut caller: CallerType,
+    template_fd: i32,
+    max_entries: u32, // Use the one of the template if zero
+) -> i32 {
+    debug!("create inner map");
+    let state = caller.data_mut();
+    if !state.is_known_map_fd(template_fd) {
+        debug!("No template map with fd `{}` found", template_fd);
+        return -ENOENT;
+    }
+    if !state.can_create(BpfResource::HostMap) {
+        return -EMFILE;
+    }
+    let template = match query_map_info(template_fd) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Failed to query info of map {}: {}", template_fd, err);
+            return err;
+        }
+    };
+    let opts = bpf_map_create_opts {
+        sz: std::mem::size_of::<bpf_map_create_opts>() as _,
+        map_flags: template.map_flags,
+        ..Default::default()
+    };
+    let fd = unsafe {
+        bpf_map_create(
+            template.type_,
+            template.name.as_ptr(),
+            template.key_size,
+            template.value_size,
+            if max_entries == 0 {
+                template.max_entries
+            } else {
+                max_entries
+            },
+            &opts,
+        )
+    };
+    if fd < 0 {
+        debug!("Failed to create inner map: {}", fd);
+        return fd;
+    }
+    return state.register_host_map(fd);
+}
+
+pub fn wasm_bpf_map_insert_inner(
+    mut caller: CallerType,
+    outer_fd: i32,
+    key: WasmPointer,
+    inner_fd: i32,
+    flags: u64,
+) -> i32 {
+    debug!("insert inner map");
+    let key_size = match outer_map_key_size(caller.data(), outer_fd) {
+        Ok(v) => v,
+        Err(err) => return err,
+    };
+    if !caller.data().is_known_map_fd(inner_fd) {
+        debug!("No inner map with fd `{}` found", inner_fd);
+        return -ENOENT;
+    }
+    ensure_enough_memory!(caller, key, key_size, -EINVAL);
+    let value = inner_fd as u32;
+    let ret_val = unsafe {
+        bpf_map_update_elem(
+            outer_fd,
+            caller.raw_pointer_at_unchecked(key as usize) as *const _,
+            &value as *const u32 as *const c_void,
+            flags,
+        )
+    };
+    if ret_val != 0 {
+        debug!("Failed to insert inner map: {}", ret_val);
+    }
+    return ret_val;
+}
+
+/// Returns a runtime-tracked fd of the inner map stored at `key`
+pub fn wasm_bpf_map_lookup_inner(mut caller: CallerType, outer_fd: i32, key: WasmPointer) -> i32 {
+    debug!("lookup inner map");
+    let key_size = match outer_map_key_size(caller.data(), outer_fd) {
+        Ok(v) => v,
+        Err(err) => return err,
+    };
+    ensure_enough_memory!(caller, key, key_size, -EINVAL);
+    let mut id = 0u32;
+    let ret_val = unsafe {
+        bpf_map_lookup_elem(
+            outer_fd,
+            caller.raw_pointer_at_unchecked(key as usize) as *const _,
+            &mut id as *mut u32 as *mut c_void,
+        )
+    };
+    if ret_val != 0 {
+        debug!("Failed to lookup outer map: {}", ret_val);
+        return ret_val;
+    }
+    let state = caller.data_mut();
+    if let Some(map) = state.host_maps.values().find(|v| v.info.id == id) {
+        return map.fd.as_raw_fd();
+    }
+    if !state.can_create(BpfResource::HostMap) {
+        return -EMFILE;
+    }
+    let fd = unsafe { bpf_map_get_fd_by_id(id) };
+    if fd < 0 {
+        debug!("Failed to get fd of map id {}: {}", id, fd);
+        return fd;
+    }
+    return state.register_host_map(fd);
+}
+
+/// Close a map tracked by the runtime. Maps owned by objects can't be closed
+pub fn wasm_bpf_map_close(mut caller: CallerType, fd: i32) -> i32 {
+    debug!("close map {}", fd);
+    let state = caller.data_mut();
+    match state.host_maps.remove(&fd) {
+        Some(_) => {
+            // The fd may be reused by maps opened afterwards
+            state.user_ringbufs.remove(&fd);
+            0
+        }
+        None => {
+            debug!("No host map with fd `{}` found", fd);
+            -ENOENT
+        }
+    }
+}
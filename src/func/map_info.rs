@@ -0,0 +1,78 @@
+use log::debug;
+
+use crate::{
+    ensure_c_str, ensure_program_mut_by_caller,
+    func::{EINVAL, ENOENT},
+    state::{query_map_info, CallerType},
+    utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer, WasmString};
+
+/// The struct filled for the guest by `wasm_bpf_map_info`
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WasmMapInfo {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub name: [u8; 16],
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+}
+
+/// Query the metadata of a map. If `name` is not null, the map named `name` in `program`
+/// will be queried; Otherwise the map with `fd` will be queried.
+pub fn wasm_bpf_map_info(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    name: WasmString, // Allow null pointers
+    info: WasmPointer,
+) -> i32 {
+    debug!("map info");
+    let fd = if name == 0 {
+        if !caller.data().is_known_map_fd(fd) {
+            debug!("No map with fd `{}` found", fd);
+            return -ENOENT;
+        }
+        fd
+    } else {
+        let map_name = ensure_c_str!(caller, name);
+        let object = ensure_program_mut_by_caller!(caller, program);
+        match object.get_object().map(&map_name) {
+            Some(v) => v.fd(),
+            None => {
+                debug!("Invalid map name: {}", map_name);
+                return -ENOENT;
+            }
+        }
+    };
+    let raw_info = match query_map_info(fd) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Failed to query info of map {}: {}", fd, err);
+            return err;
+        }
+    };
+    let mut result = WasmMapInfo {
+        map_type: raw_info.type_,
+        key_size: raw_info.key_size,
+        value_size: raw_info.value_size,
+        max_entries: raw_info.max_entries,
+        map_flags: raw_info.map_flags,
+        btf_key_type_id: raw_info.btf_key_type_id,
+        btf_value_type_id: raw_info.btf_value_type_id,
+        ..Default::default()
+    };
+    for (dst, src) in result.name.iter_mut().zip(raw_info.name.iter()) {
+        *dst = *src as u8;
+    }
+    if let Err(err) = caller.write_wasm_struct(info as usize, &result) {
+        debug!("Invalid pointer for info: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
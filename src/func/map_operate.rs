@@ -16,16 +16,12 @@ pub fn wasm_bpf_map_operate(
 ) -> i32 {
     debug!("Map operate");
     // let mut map = None;
-    let (key_size, value_size, fd) = {
-        let state = caller.data();
-
-        let map = if let Some(v) = state.get_map_by_fd(fd) {
-            v
-        } else {
+    let (key_size, value_size) = match caller.data().get_map_key_value_size_by_fd(fd) {
+        Some(v) => v,
+        None => {
             debug!("No map with fd `{}` found", fd);
             return ENOENT;
-        };
-        (map.key_size() as usize, map.value_size() as usize, map.fd())
+        }
     };
 
     match cmd as u32 {
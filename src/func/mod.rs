@@ -1,6 +1,13 @@
 
 pub const EINVAL: i32 = 22;
 pub const ENOENT: i32 = 2;
+pub const E2BIG: i32 = 7;
+pub const ENOSPC: i32 = 28;
+pub const ECANCELED: i32 = 125;
+pub const EAGAIN: i32 = 11;
+pub const EBUSY: i32 = 16;
+pub const EINTR: i32 = 4;
+pub const EMFILE: i32 = 24;
 
 pub mod poll;
 pub mod load;
@@ -8,7 +15,19 @@ pub mod close;
 pub mod attach;
 pub mod fd_by_name;
 pub mod map_operate;
+pub mod map_in_map;
+pub mod map_create;
+pub mod map_info;
+pub mod object_info;
+pub mod test_run;
+pub mod prog_stats;
+pub mod iter;
+pub mod user_ringbuf;
+pub mod queue;
+pub mod buffer_file;
 pub mod wrapper_poll;
+#[cfg(feature = "async")]
+pub mod async_poll;
 #[macro_export]
 macro_rules! ensure_program_mut_by_state {
     ($state: expr, $program: expr) => {
@@ -25,7 +44,7 @@ macro_rules! ensure_program_mut_by_state {
 macro_rules! ensure_program_mut_by_caller {
     ($caller: expr, $program: expr) => {
         {
-            use crate::ensure_program_mut_by_state;
+            use $crate::ensure_program_mut_by_state;
             ensure_program_mut_by_state!($caller.data_mut(), $program)
         }
     };
@@ -35,7 +54,7 @@ macro_rules! ensure_program_mut_by_caller {
 macro_rules! ensure_c_str {
     ($caller: expr, $var_name: expr) => {
         {
-            use crate::utils::CallerUtils;   
+            use $crate::utils::CallerUtils;
             match $caller.read_zero_terminated_str($var_name as usize) {
                 Ok(v) => v.to_string(),
                 Err(err) => {
@@ -55,7 +74,7 @@ pub type WasmString = u32;
 #[macro_export]
 macro_rules! ensure_enough_memory {
     ($caller: expr, $pointer:expr, $size: expr, $return_val: expr) => {{
-        use crate::utils::CallerUtils;
+        use $crate::utils::CallerUtils;
         let mut buf = vec![0u8];
         match $caller
             .get_memory()
@@ -0,0 +1,118 @@
+use log::debug;
+
+use crate::{
+    ensure_program_mut_by_caller,
+    func::{EINVAL, ENOENT},
+    state::CallerType,
+    utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer};
+
+/// Write `s` with the terminating zero into a guest buffer of `buf_len` bytes
+fn write_c_str(caller: &mut CallerType, buf: WasmPointer, buf_len: u32, s: &str) -> bool {
+    if s.len() + 1 > buf_len as usize {
+        debug!(
+            "Buffer too small for `{}`: {} bytes required, {} provided",
+            s,
+            s.len() + 1,
+            buf_len
+        );
+        return false;
+    }
+    let memory = caller.get_memory().expect("Expected exported memory!");
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    if let Err(err) = memory.write(&mut *caller, buf as usize, &bytes) {
+        debug!("Invalid pointer for buf: {}", err);
+        return false;
+    }
+    return true;
+}
+
+/// Returns the number of programs in the object.
+/// Programs are indexed in the order of their names
+pub fn wasm_bpf_object_prog_count(mut caller: CallerType, program: BpfObjectType) -> i32 {
+    debug!("object prog count");
+    let object = ensure_program_mut_by_caller!(caller, program);
+    return object.get_object().progs_iter().count() as i32;
+}
+
+/// Fill the name, section name and type of the `index`-th program of the object
+pub fn wasm_bpf_object_prog_info(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    index: u32,
+    name: WasmPointer,
+    section: WasmPointer,
+    buf_len: u32, // Size of both `name` and `section`
+    prog_type: WasmPointer,
+) -> i32 {
+    debug!("object prog info");
+    let object = ensure_program_mut_by_caller!(caller, program);
+    let mut progs = object
+        .get_object()
+        .progs_iter()
+        .map(|v| (v.name().to_string(), v.section().to_string(), v.prog_type()))
+        .collect::<Vec<_>>();
+    progs.sort_by(|a, b| a.0.cmp(&b.0));
+    let (prog_name, prog_section, ty) = match progs.into_iter().nth(index as usize) {
+        Some(v) => v,
+        None => {
+            debug!("No program with index {}", index);
+            return -ENOENT;
+        }
+    };
+    if !write_c_str(&mut caller, name, buf_len, &prog_name)
+        || !write_c_str(&mut caller, section, buf_len, &prog_section)
+    {
+        return -EINVAL;
+    }
+    if let Err(err) = caller.write_wasm_struct(prog_type as usize, &(ty as u32)) {
+        debug!("Invalid pointer for prog_type: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
+
+/// Returns the number of maps in the object, including the internal ones
+/// like `.rodata` and `.bss`. Maps are indexed in the order of their names
+pub fn wasm_bpf_object_map_count(mut caller: CallerType, program: BpfObjectType) -> i32 {
+    debug!("object map count");
+    let object = ensure_program_mut_by_caller!(caller, program);
+    return object.get_object().maps_iter().count() as i32;
+}
+
+/// Fill the name and type of the `index`-th map of the object
+pub fn wasm_bpf_object_map_info(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    index: u32,
+    name: WasmPointer,
+    buf_len: u32,
+    map_type: WasmPointer,
+) -> i32 {
+    debug!("object map info");
+    let object = ensure_program_mut_by_caller!(caller, program);
+    let mut maps = object
+        .get_object()
+        .maps_iter()
+        .map(|v| (v.name().to_string(), v.map_type()))
+        .collect::<Vec<_>>();
+    maps.sort_by(|a, b| a.0.cmp(&b.0));
+    let (map_name, ty) = match maps.into_iter().nth(index as usize) {
+        Some(v) => v,
+        None => {
+            debug!("No map with index {}", index);
+            return -ENOENT;
+        }
+    };
+    if !write_c_str(&mut caller, name, buf_len, &map_name) {
+        return -EINVAL;
+    }
+    if let Err(err) = caller.write_wasm_struct(map_type as usize, &(ty as u32)) {
+        debug!("Invalid pointer for map_type: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
@@ -1,22 +1,29 @@
 use std::{
+    collections::{HashMap, VecDeque},
     ffi::c_void,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
     ptr::{null, null_mut},
     slice::from_raw_parts,
 };
 
 use libbpf_rs::libbpf_sys::{
     bpf_map, bpf_map__fd, bpf_map__set_autocreate, bpf_map__set_key_size, bpf_map__set_type,
-    bpf_map__set_value_size, bpf_map__type, bpf_map_type, perf_buffer, perf_buffer__free,
-    perf_buffer__new, perf_buffer__poll, ring_buffer, ring_buffer__free, ring_buffer__new,
-    ring_buffer__poll, BPF_MAP_TYPE_PERF_EVENT_ARRAY, BPF_MAP_TYPE_RINGBUF,
+    bpf_map__set_value_size, bpf_map__type, bpf_map_type, bpf_perf_event_ret, perf_buffer,
+    perf_buffer__consume, perf_buffer__epoll_fd, perf_buffer__free, perf_buffer__new_raw,
+    perf_buffer__poll, perf_event_attr, perf_event_header, ring_buffer, ring_buffer__add,
+    ring_buffer__consume, ring_buffer__epoll_fd, ring_buffer__free, ring_buffer__new,
+    ring_buffer__poll, BPF_MAP_TYPE_PERF_EVENT_ARRAY, BPF_MAP_TYPE_RINGBUF, LIBBPF_PERF_EVENT_CONT,
+    LIBBPF_PERF_EVENT_DONE, PERF_COUNT_SW_BPF_OUTPUT, PERF_RECORD_LOST, PERF_RECORD_SAMPLE,
+    PERF_SAMPLE_RAW, PERF_TYPE_SOFTWARE,
 };
 use log::{debug, error};
-use wasmtime::Val;
+use wasmtime::{Func, TypedFunc, Val};
 
 use crate::{
+    deadline::call_callback,
     ensure_enough_memory, ensure_program_mut_by_state,
-    func::{EINVAL, ENOENT},
-    state::{CallerType, PollWrapper},
+    func::{ECANCELED, EINVAL, ENOENT},
+    state::{query_map_info, AppState, CallerType, PollWrapper},
     utils::{CallerUtils, FunctionQuickCall},
 };
 
@@ -24,12 +31,91 @@ use super::{BpfObjectType, WasmPointer};
 
 pub const PERF_BUFFER_PAGES: u64 = 64;
 
+/// Options used when opening perf buffers
+#[derive(Clone, Copy, Debug)]
+pub struct PerfBufferOptions {
+    // Number of pages of the buffer of each CPU, must be a power of 2
+    pub page_cnt: usize,
+    // Wake up the poller every `wakeup_events` samples, zero for the default behavior
+    pub wakeup_events: u32,
+    // Wake up the poller once `wakeup_watermark` bytes are available, zero for not used
+    pub wakeup_watermark: u32,
+}
+
+impl Default for PerfBufferOptions {
+    fn default() -> Self {
+        Self {
+            page_cnt: PERF_BUFFER_PAGES as _,
+            wakeup_events: 0,
+            wakeup_watermark: 0,
+        }
+    }
+}
+
+impl PerfBufferOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.page_cnt.is_power_of_two() {
+            return Err(format!(
+                "Page count of perf buffers must be a power of 2, got {}",
+                self.page_cnt
+            ));
+        }
+        if self.wakeup_events != 0 && self.wakeup_watermark != 0 {
+            return Err("Only one of wakeup events and wakeup watermark can be set".into());
+        }
+        Ok(())
+    }
+    /// The attr of the perf events which output samples of a perf buffer
+    pub fn build_perf_event_attr(&self) -> perf_event_attr {
+        let mut attr = perf_event_attr {
+            type_: PERF_TYPE_SOFTWARE,
+            size: std::mem::size_of::<perf_event_attr>() as _,
+            config: PERF_COUNT_SW_BPF_OUTPUT as _,
+            sample_type: PERF_SAMPLE_RAW as _,
+            ..Default::default()
+        };
+        attr.__bindgen_anon_1.sample_period = 1;
+        if self.wakeup_watermark != 0 {
+            attr.set_watermark(1);
+            attr.__bindgen_anon_2.wakeup_watermark = self.wakeup_watermark;
+        } else if self.wakeup_events != 0 {
+            attr.__bindgen_anon_2.wakeup_events = self.wakeup_events;
+        } else {
+            // The default of `perf_buffer__new`
+            attr.__bindgen_anon_2.wakeup_events = 1;
+        }
+        attr
+    }
+}
+
 pub struct SampleContext {
     pub wasm_ctx: u32,
     pub store_ptr: *mut CallerType<'static>,
     pub callback_index: u32,
     pub raw_wasm_data_buffer: u32,
     pub max_size: usize,
+    // Set once the guest configured the callback of this map
+    pub registered: bool,
+    // Pass the CPU as the second argument of the callback
+    pub with_cpu: bool,
+    // Call the callback exports of the guest instead of the functions in its table
+    pub via_wrapper: bool,
+    // The CPU of the sample being delivered, -1 for ring buffers
+    pub cpu: i32,
+    // Zero if no lost callback is set
+    pub lost_callback_index: u32,
+    pub samples: u64,
+    pub lost_samples: u64,
+    // Pack samples into the arena at `raw_wasm_data_buffer` instead of calling back for each one
+    pub batch: bool,
+    // Bytes used in the arena and records packed into it
+    pub batch_offset: usize,
+    pub batch_count: u32,
+    // BUFFER_FLAG_* set by `wasm_bpf_buffer_set_flags`
+    pub flags: u32,
+    // Records consumed from the buffer but not delivered since the guest stopped the poll,
+    // delivered first by the next poll
+    pub carried_records: VecDeque<CarriedRecord>,
 }
 
 impl Default for SampleContext {
@@ -40,63 +126,477 @@ impl Default for SampleContext {
             callback_index: Default::default(),
             raw_wasm_data_buffer: Default::default(),
             max_size: Default::default(),
+            registered: false,
+            with_cpu: false,
+            via_wrapper: false,
+            cpu: -1,
+            lost_callback_index: 0,
+            samples: 0,
+            lost_samples: 0,
+            flags: 0,
+            batch: false,
+            batch_offset: 0,
+            batch_count: 0,
+            carried_records: VecDeque::new(),
         }
     }
 }
 
-pub type SampleCallbackParams = (u32, u32, u32);
-pub type SampleCallbackReturn = ();
+/// A record kept by `SampleContext::carried_records`, with the CPU which produced it
+pub enum CarriedRecord {
+    Sample(i32, Vec<u8>),
+    Lost(i32, u64),
+}
+
+/// Counters of a buffer filled for the guest by `wasm_bpf_buffer_stats`
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WasmBufferStats {
+    pub samples: u64,
+    pub lost_samples: u64,
+}
+
+/// Pass the length of data copied to the callback instead of the size of the sample,
+/// with `SAMPLE_TRUNCATED_BIT` set if the sample doesn't fit in the buffer
+pub const BUFFER_FLAG_MARK_TRUNCATED: u32 = 1 << 0;
+/// Copy each sample into memory returned by the allocator export of the guest, which
+/// must be freed by the guest. Falls back to the poll buffer if the allocation fails.
+pub const BUFFER_FLAG_ALLOC_SAMPLES: u32 = 1 << 1;
+pub const BUFFER_FLAGS_ALL: u32 = BUFFER_FLAG_MARK_TRUNCATED | BUFFER_FLAG_ALLOC_SAMPLES;
+pub const SAMPLE_TRUNCATED_BIT: u32 = 1 << 31;
+
+/// Header of each record packed by `wasm_bpf_buffer_poll_batch`, followed by
+/// `len` bytes of data. Records are aligned to 8 bytes.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WasmBatchRecord {
+    // Bytes of data in this record
+    pub len: u32,
+    // Size of the sample, larger than `len` if it's truncated to fit in the arena
+    pub size: u32,
+    // The CPU which produced the sample, -1 for ring buffers
+    pub cpu: i32,
+    pub reserved: u32,
+}
+
 pub type SampleCallbackWrapper = extern "C" fn(*mut c_void, *mut c_void, u64) -> i32;
+/// Returned to libbpf to stop consuming ring buffers, the code returned by the poll
+/// is kept in `AppState::poll_stop_code`
+pub const STOP_POLLING: i32 = -ECANCELED;
 extern "C" fn sample_function_wrapper(ctx: *mut c_void, data: *mut c_void, size: u64) -> i32 {
-    let ctx = unsafe { &*(ctx as *mut SampleContext) };
+    let ctx = unsafe { &mut *(ctx as *mut SampleContext) };
     let caller = unsafe { &mut *ctx.store_ptr };
-    let available_length = ctx.max_size.min(size as usize);
+    ctx.samples += 1;
+    let data = unsafe { from_raw_parts(data as *const u8, size as usize) };
+    deliver_sample(ctx, caller, data)
+}
+
+/// Pass a sample to the callback of the guest or pack it into the arena of a batch,
+/// returns STOP_POLLING if the guest stopped the poll
+fn deliver_sample(ctx: &mut SampleContext, caller: &mut CallerType, data: &[u8]) -> i32 {
+    if ctx.batch {
+        return batch_sample(ctx, caller, data);
+    }
+    let size = data.len();
+    let mut wasm_buffer = ctx.raw_wasm_data_buffer;
+    let mut available_length = ctx.max_size.min(size);
+    if ctx.flags & BUFFER_FLAG_ALLOC_SAMPLES != 0 {
+        if let Some(ptr) = alloc_guest_memory(caller, size as u32) {
+            wasm_buffer = ptr;
+            available_length = size;
+        }
+    }
     let memory = caller.get_memory().expect("Memory must be exported");
-    if let Err(e) = memory.write(&mut *caller, ctx.raw_wasm_data_buffer as usize, unsafe {
-        from_raw_parts(data as *const u8, available_length)
-    }) {
+    if let Err(e) = memory.write(
+        &mut *caller,
+        wasm_buffer as usize,
+        &data[..available_length],
+    ) {
         error!("Failed to write wasm memory: {}", e);
         return 0;
     }
+    let size = sample_size(size, available_length, ctx.flags);
+    let func = match find_callback(
+        caller,
+        CallbackKind::Sample,
+        ctx.via_wrapper,
+        ctx.callback_index,
+    ) {
+        Ok(v) => v,
+        Err(MissingCallback::Export) => {
+            caller.data_mut().poll_stop_code = Some(-EINVAL);
+            return STOP_POLLING;
+        }
+        Err(MissingCallback::TableEntry) => return 0,
+    };
+    let params = sample_callback_params(
+        ctx.wasm_ctx,
+        ctx.with_cpu.then_some(ctx.cpu),
+        wasm_buffer,
+        size,
+    );
+    let result = call_sample_callback(caller, func, &params);
+    sample_callback_result(caller, result)
+}
+
+/// Size passed to the sample callback for a sample of `size` bytes of which `available`
+/// bytes are passed. It's the size of the whole sample unless truncation is marked.
+pub(super) fn sample_size(size: usize, available: usize, flags: u32) -> u32 {
+    if available < size {
+        debug!("Sample of {} bytes truncated to {} bytes", size, available);
+    }
+    if flags & BUFFER_FLAG_MARK_TRUNCATED == 0 {
+        size as u32
+    } else if available < size {
+        available as u32 | SAMPLE_TRUNCATED_BIT
+    } else {
+        available as u32
+    }
+}
+
+/// Callbacks of the guest called by the poll functions
+#[derive(Clone, Copy, Debug)]
+pub(super) enum CallbackKind {
+    Sample,
+    Batch,
+    Lost,
+}
+
+/// A callback which `find_callback` didn't find
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum MissingCallback {
+    // The export named by the poll wrapper
+    Export,
+    // The entry of the table passed by the guest
+    TableEntry,
+}
+
+/// Find the callback of `kind`: the export named by the poll wrapper if the map is
+/// polled `via_wrapper`, or else the function at `index` of the table of the guest
+pub(super) fn find_callback(
+    caller: &mut CallerType,
+    kind: CallbackKind,
+    via_wrapper: bool,
+    index: u32,
+) -> Result<Func, MissingCallback> {
     if let PollWrapper::Enabled {
         callback_function_name,
-    } = caller.data().poll_wrapper.clone()
+        lost_callback_function_name,
+        batch_callback_function_name,
+    } = &caller.data().poll_wrapper
     {
-        let mut result = [Val::I32(0)];
-        if let Err(err) = caller
-            .get_export(&callback_function_name)
-            .unwrap()
-            .into_func()
-            .unwrap()
-            .call(
-                &mut *caller,
-                &[
-                    // Seems that tinygo cannot produce unsigned integer types, so just let wasmtiime to perform the conversion
-                    Val::I32(ctx.wasm_ctx as _),
-                    Val::I32(ctx.raw_wasm_data_buffer as _),
-                    Val::I32(size as _),
-                ],
-                &mut result,
-            )
-        {
-            error!("Failed to call the callback through direct export: {}", err);
-            return 0;
+        if via_wrapper {
+            let name = match kind {
+                CallbackKind::Sample => callback_function_name,
+                CallbackKind::Batch => batch_callback_function_name,
+                CallbackKind::Lost => lost_callback_function_name,
+            }
+            .clone();
+            return match caller.get_export(&name).and_then(|v| v.into_func()) {
+                Some(v) => Ok(v),
+                None => {
+                    debug!("{:?} callback export named {} not found", kind, name);
+                    Err(MissingCallback::Export)
+                }
+            };
         }
-    } else {
-        if let Err(e) = caller.perform_indirect_call::<SampleCallbackParams, SampleCallbackReturn>(
-            ctx.callback_index,
-            (ctx.wasm_ctx, ctx.raw_wasm_data_buffer, size as u32),
-        ) {
-            error!("Failed to perform indirect call when polling: {}", e);
-            return 0;
+    }
+    match caller.get_indirect_function(index) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            error!("Failed to get the {:?} callback: {}", kind, e);
+            Err(MissingCallback::TableEntry)
+        }
+    }
+}
+
+/// Parameters of a sample callback, `cpu` is only passed to callbacks taking it
+pub(super) fn sample_callback_params(ctx: u32, cpu: Option<i32>, data: u32, size: u32) -> Vec<Val> {
+    // Seems that tinygo cannot produce unsigned integer types, so just let wasmtiime to perform the conversion
+    let mut params = vec![Val::I32(ctx as _)];
+    if let Some(cpu) = cpu {
+        params.push(Val::I32(cpu));
+    }
+    params.push(Val::I32(data as _));
+    params.push(Val::I32(size as _));
+    params
+}
+
+/// Value returned by a sample callback, which may return nothing or an i32
+pub(super) fn sample_callback_code(results: &[Val]) -> i32 {
+    results.first().and_then(|v| v.i32()).unwrap_or(0)
+}
+
+/// Returns STOP_POLLING if the result of a sample callback stops the poll, and
+/// keeps why in `poll_stop_code`. Failed calls are logged and skipped unless a
+/// deadline stopped them.
+pub(super) fn sample_callback_result(caller: &mut CallerType, result: anyhow::Result<i32>) -> i32 {
+    match result {
+        Ok(0) => 0,
+        Ok(code) => {
+            debug!("Polling stopped by the callback: {}", code);
+            caller.data_mut().poll_stop_code = Some(code);
+            STOP_POLLING
+        }
+        Err(e) if caller.data().deadlines.exceeded().is_some() => {
+            debug!("Polling stopped by a deadline: {}", e);
+            caller.data_mut().poll_stop_code = Some(-ECANCELED);
+            STOP_POLLING
+        }
+        Err(e) => {
+            error!("Failed to call the callback when polling: {}", e);
+            0
+        }
+    }
+}
+
+/// Allocate `size` bytes with the allocator export of the guest, None if it fails
+fn alloc_guest_memory(caller: &mut CallerType, size: u32) -> Option<u32> {
+    let func = find_allocator(caller)?;
+    let ptr = call_callback(caller, |caller| func.call(caller, size));
+    allocated_memory(caller, ptr, size)
+}
+
+/// The allocator export of the guest, see `BUFFER_FLAG_ALLOC_SAMPLES`
+pub(super) fn find_allocator(caller: &mut CallerType) -> Option<TypedFunc<u32, u32>> {
+    let name = caller.data().allocator_export_name.clone();
+    let func = match caller.get_export(&name).and_then(|v| v.into_func()) {
+        Some(v) => v,
+        None => {
+            debug!("Allocator export named {} not found", name);
+            return None;
+        }
+    };
+    match func.typed::<u32, u32>(&mut *caller) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            error!("Invalid allocator {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// The memory allocated by the allocator for `size` bytes, None if it failed
+pub(super) fn allocated_memory(
+    caller: &CallerType,
+    ptr: anyhow::Result<u32>,
+    size: u32,
+) -> Option<u32> {
+    match ptr {
+        Ok(0) => {
+            debug!("Failed to allocate {} bytes in the guest", size);
+            None
+        }
+        Ok(v) => Some(v),
+        Err(e) => {
+            error!(
+                "Failed to call the allocator {}: {}",
+                caller.data().allocator_export_name,
+                e
+            );
+            None
+        }
+    }
+}
+
+fn call_sample_callback(
+    caller: &mut CallerType,
+    func: Func,
+    params: &[Val],
+) -> anyhow::Result<i32> {
+    let mut results = vec![Val::I32(0); func.ty(&*caller).results().len()];
+    call_callback(caller, |caller| func.call(caller, params, &mut results))?;
+    Ok(sample_callback_code(&results))
+}
+
+pub(super) fn batch_record_size(len: usize) -> usize {
+    (std::mem::size_of::<WasmBatchRecord>() + len).next_multiple_of(8)
+}
+
+/// Pack a sample into the arena, delivering the full arena to the guest first if it doesn't fit
+fn batch_sample(ctx: &mut SampleContext, caller: &mut CallerType, data: &[u8]) -> i32 {
+    if ctx.batch_count > 0 && ctx.batch_offset + batch_record_size(data.len()) > ctx.max_size {
+        let code = flush_batch(ctx, caller);
+        if code != 0 {
+            debug!("Polling stopped by the batch callback: {}", code);
+            caller.data_mut().poll_stop_code = Some(code);
+            // The sample is already consumed from the buffer, so it goes first in the next batch
+            ctx.carried_records
+                .push_front(CarriedRecord::Sample(ctx.cpu, data.to_vec()));
+            return STOP_POLLING;
+        }
+    }
+    pack_batch_record(ctx, caller, ctx.cpu, data);
+    return 0;
+}
+
+fn pack_batch_record(ctx: &mut SampleContext, caller: &mut CallerType, cpu: i32, data: &[u8]) {
+    let arena = ctx.raw_wasm_data_buffer;
+    if let Some(size) = write_batch_record(caller, arena, ctx.batch_offset, ctx.max_size, cpu, data)
+    {
+        ctx.batch_offset += size;
+        ctx.batch_count += 1;
+    }
+}
+
+/// The record of a sample of `size` bytes packed at `offset` of an arena of `max_size`
+/// bytes. Only samples larger than the whole arena are truncated.
+pub(super) fn batch_record(
+    offset: usize,
+    max_size: usize,
+    cpu: i32,
+    size: usize,
+) -> WasmBatchRecord {
+    let header_size = std::mem::size_of::<WasmBatchRecord>();
+    let len = size.min(max_size.saturating_sub(offset + header_size));
+    WasmBatchRecord {
+        len: len as u32,
+        size: size as u32,
+        cpu,
+        reserved: 0,
+    }
+}
+
+/// Write the record of a sample at `offset` of the arena at `arena`, returns the bytes
+/// used by the record or None if the memory can't be written
+pub(super) fn write_batch_record(
+    caller: &mut CallerType,
+    arena: WasmPointer,
+    offset: usize,
+    max_size: usize,
+    cpu: i32,
+    data: &[u8],
+) -> Option<usize> {
+    let header_size = std::mem::size_of::<WasmBatchRecord>();
+    let record = batch_record(offset, max_size, cpu, data.len());
+    let offset = arena as usize + offset;
+    if let Err(e) = caller.write_wasm_struct(offset, &record) {
+        error!("Failed to write wasm memory: {}", e);
+        return None;
+    }
+    let memory = caller.get_memory().expect("Memory must be exported");
+    let data = &data[..record.len as usize];
+    if let Err(e) = memory.write(&mut *caller, offset + header_size, data) {
+        error!("Failed to write wasm memory: {}", e);
+        return None;
+    }
+    Some(batch_record_size(data.len()))
+}
+
+/// Call the batch callback with the packed records, returns the value returned by it
+fn flush_batch(ctx: &mut SampleContext, caller: &mut CallerType) -> i32 {
+    let count = ctx.batch_count;
+    ctx.batch_offset = 0;
+    ctx.batch_count = 0;
+    let func = match find_callback(
+        caller,
+        CallbackKind::Batch,
+        ctx.via_wrapper,
+        ctx.callback_index,
+    ) {
+        Ok(v) => v,
+        Err(MissingCallback::Export) => return -EINVAL,
+        Err(MissingCallback::TableEntry) => return 0,
+    };
+    let params = [
+        Val::I32(ctx.wasm_ctx as _),
+        Val::I32(ctx.raw_wasm_data_buffer as _),
+        Val::I32(count as _),
+    ];
+    match call_sample_callback(caller, func, &params) {
+        Ok(code) => code,
+        Err(e) if caller.data().deadlines.exceeded().is_some() => {
+            debug!("Polling stopped by a deadline: {}", e);
+            -ECANCELED
+        }
+        Err(e) => {
+            error!("Failed to call the batch callback: {}", e);
+            0
         }
     }
+}
 
+/// Open the buffer consuming the map `fd` of `program` if it's not opened yet
+fn ensure_buffer_opened(state: &mut AppState, program: BpfObjectType, fd: i32) -> i32 {
+    let map_ptr = unsafe { state.get_map_ptr_by_fd(fd) };
+    let perf_options = state.perf_buffer_options;
+    let object = ensure_program_mut_by_state!(state, program);
+    if object.get_buffer_by_fd_mut(fd).is_some() {
+        return 0;
+    }
+    if let Err(err) = state.check_new_consumer(program, fd) {
+        return err;
+    }
+    let object = ensure_program_mut_by_state!(state, program);
+    // The map belongs to the object, so it's found
+    let map_ptr = map_ptr.unwrap() as *mut bpf_map;
+    let is_ringbuf = unsafe { bpf_map__type(map_ptr) } == BPF_MAP_TYPE_RINGBUF;
+    // All ring buffer maps of an object are consumed together by one `ring_buffer`
+    let res = match object.get_ring_buffer_mut() {
+        Some(buffer) if is_ringbuf => {
+            buffer.bpf_buffer__add(map_ptr, sample_function_wrapper, Default::default())
+        }
+        _ => {
+            let mut buffer = unsafe { BpfBuffer::bpf_buffer__new(map_ptr) };
+            buffer.perf_options = perf_options;
+            let res = buffer.bpf_buffer__open(sample_function_wrapper, Default::default());
+            if res == 0 {
+                object.buffers.push(buffer);
+            }
+            res
+        }
+    };
+    if res != 0 {
+        debug!("Failed to open buffer for map fd {}: {}", fd, res);
+        return res;
+    }
+    if let Some(flags) = object.pending_buffer_flags.remove(&fd) {
+        let buffer = object.get_buffer_by_fd_mut(fd).unwrap();
+        buffer.host_ctx_boxes.get_mut(&fd).unwrap().flags = flags;
+    }
     return 0;
 }
 
+/// Check if the map `fd` is a ring buffer map which has no buffer yet. Such a map
+/// isn't added to the ring buffer of its object until it has a callback, since
+/// polling the other maps would consume its samples.
+fn is_unopened_ringbuf(state: &mut AppState, program: BpfObjectType, fd: i32) -> bool {
+    let map_ptr = match unsafe { state.get_map_ptr_by_fd(fd) } {
+        Some(v) => v as *mut bpf_map,
+        None => return false,
+    };
+    if unsafe { bpf_map__type(map_ptr) } != BPF_MAP_TYPE_RINGBUF {
+        return false;
+    }
+    match state.object_map.get_mut(&program) {
+        Some(object) => object.get_buffer_by_fd_mut(fd).is_none(),
+        None => false,
+    }
+}
+
+/// Returns 1 if `fd` is readable, 0 if not, or a negative error code
+fn is_fd_readable(fd: i32) -> i32 {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut poll_fd, 1, 0) };
+    if res < 0 {
+        let err = std::io::Error::last_os_error();
+        debug!("Failed to check the readiness of fd {}: {}", fd, err);
+        return -err.raw_os_error().unwrap_or(EINVAL);
+    }
+    return (res > 0) as i32;
+}
+
+/// Poll the buffer consuming map `fd`, calling `callback(ctx, data, size)` for each sample.
+/// A non-zero value returned by the callback stops the poll and is returned from here.
+/// The poll returns -ECANCELED if the timeout of the run passes while it waits, and
+/// -EINVAL if the callback export of the poll wrapper is missing. Invalid arguments are
+/// reported as positive EINVAL and ENOENT like the first versions of wasm-bpf.
 pub fn wasm_bpf_buffer_poll(
-    mut caller: CallerType,
+    caller: CallerType,
     program: BpfObjectType,
     fd: i32,
     sample_func: WasmPointer,
@@ -106,43 +606,625 @@ pub fn wasm_bpf_buffer_poll(
     timeout_ms: i32,
 ) -> i32 {
     debug!("bpf buffer poll");
-    let caller_ptr = &caller as *const CallerType as *mut CallerType<'static>;
+    buffer_poll(
+        caller,
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        Some(timeout_ms),
+        false,
+        false,
+        false,
+    )
+    .unwrap_or_else(legacy_poll_error)
+}
+
+/// Same as `wasm_bpf_buffer_poll`, but the callback also receives the CPU which
+/// produced the sample: `callback(ctx, cpu, data, size)`. The CPU is -1 for ring buffers.
+/// All errors are negative.
+pub fn wasm_bpf_buffer_poll_with_cpu(
+    caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!("bpf buffer poll with cpu");
+    buffer_poll(
+        caller,
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        Some(timeout_ms),
+        true,
+        false,
+        false,
+    )
+    .unwrap_or_else(|err| err)
+}
+
+/// Poll the buffer consuming map `fd`, packing samples into the arena at `data` of
+/// `max_size` bytes as `WasmBatchRecord`s. `callback(ctx, data, count)` is called each
+/// time the arena is full and at the end of the poll, instead of once per sample.
+/// A non-zero value returned by the callback stops the poll like `wasm_bpf_buffer_poll`.
+/// Pass zero as `batch_func` to call the export named by `--batch-callback-export-name`.
+pub fn wasm_bpf_buffer_poll_batch(
+    caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    batch_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!("bpf buffer poll batch");
+    if (max_size as usize) < batch_record_size(1) {
+        debug!("Arena of {} bytes is too small", max_size);
+        return -EINVAL;
+    }
+    buffer_poll(
+        caller,
+        program,
+        fd,
+        batch_func,
+        ctx,
+        data,
+        max_size,
+        Some(timeout_ms),
+        false,
+        true,
+        batch_func == 0,
+    )
+    .unwrap_or_else(|err| err)
+}
+
+/// Errors of the arguments of `wasm_bpf_buffer_poll` and the poll wrapper keep the
+/// positive EINVAL and ENOENT of the first versions of wasm-bpf
+pub(super) fn legacy_poll_error(err: i32) -> i32 {
+    if err == -EINVAL || err == -ENOENT {
+        -err
+    } else {
+        err
+    }
+}
+
+/// Set the callback of map `fd` and poll its buffer. Errors of the arguments and of
+/// opening the buffer are returned as `Err`, the result of the poll as `Ok`.
+pub(super) fn buffer_poll(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: Option<i32>, // Consume the available samples without waiting if None
+    with_cpu: bool,
+    batch: bool,
+    via_wrapper: bool,
+) -> Result<i32, i32> {
+    if max_size <= 0 {
+        debug!("Invalid size of the buffer: {}", max_size);
+        return Err(-EINVAL);
+    }
     // Ensure that there is enough memory in the wasm side
-    ensure_enough_memory!(caller, data, max_size, EINVAL);
-    let state = caller.data_mut();
-    let map_ptr = unsafe { state.get_map_ptr_by_fd(fd) };
-    let object = ensure_program_mut_by_state!(state, program);
+    ensure_enough_memory!(caller, data, max_size, Err(-EINVAL));
+    let res = setup_context(
+        caller.data_mut(),
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        with_cpu,
+        batch,
+        via_wrapper,
+    );
+    if res != 0 {
+        return Err(res);
+    }
+    let res = consume_buffer(&mut caller, program, fd, timeout_ms);
+    if res < 0 && caller.data().poll_stop_code.is_none() {
+        debug!("Failed to poll: {}", res);
+    }
+    return Ok(res);
+}
 
-    if object.buffer.is_none() {
-        let map_ptr = match map_ptr {
-            Some(v) => v,
-            None => {
-                debug!("Invalid map fd: {}", fd);
-                return ENOENT;
-            }
-        };
-        let mut buffer = unsafe { BpfBuffer::bpf_buffer__new(map_ptr as *mut bpf_map) };
-        buffer.bpf_buffer__open(sample_function_wrapper, Default::default());
-        object.buffer = Some(buffer);
+/// Open the buffer consuming map `fd` and set the callback of the map, which is
+/// kept until the next call
+fn setup_context(
+    state: &mut AppState,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+    with_cpu: bool,
+    batch: bool,
+    via_wrapper: bool,
+) -> i32 {
+    let res = ensure_buffer_opened(state, program, fd);
+    if res != 0 {
+        return res;
     }
-    // modify the context we passed to bpf_buffer__open each time before we call bpf_buffer_poll
-    // the callback function will be called if and only if bpf_buffer__poll is called.
-    // So set the pointer to `CallerType` to the caller in the current context will work
-    let buffer = object.buffer.as_mut().unwrap();
-    let context = buffer.host_ctx_box.as_mut().unwrap();
+    let object = ensure_program_mut_by_state!(state, program);
+    let buffer = object.get_buffer_by_fd_mut(fd).unwrap();
+    let context = buffer.host_ctx_boxes.get_mut(&fd).unwrap();
+    context.registered = true;
     context.callback_index = sample_func;
     context.max_size = max_size as usize;
     context.raw_wasm_data_buffer = data;
-    context.store_ptr = caller_ptr;
     context.wasm_ctx = ctx;
-    let res = buffer.bpf_buffer__poll(timeout_ms);
+    context.with_cpu = with_cpu;
+    context.batch = batch;
+    context.via_wrapper = via_wrapper;
+    context.batch_offset = 0;
+    context.batch_count = 0;
+    return 0;
+}
+
+/// Poll the opened buffer consuming map `fd`, samples of all maps of the buffer are
+/// delivered to their own callbacks. Returns the code which stopped the poll, see
+/// `AppState::poll_stop_code`, or the result of libbpf if it's negative.
+fn consume_buffer(
+    caller: &mut CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    timeout_ms: Option<i32>, // Consume the available samples without waiting if None
+) -> i32 {
+    let caller_ptr = caller as *mut CallerType as *mut CallerType<'static>;
+    let state = caller.data_mut();
+    state.poll_stop_code = None;
+    let object = ensure_program_mut_by_state!(state, program);
+    // modify the context we passed to bpf_buffer__open each time before we call bpf_buffer_poll
+    // the callback function will be called if and only if bpf_buffer__poll is called.
+    // So set the pointer to `CallerType` to the caller in the current context will work
+    let buffer = object.get_buffer_by_fd_mut(fd).unwrap();
+    buffer.set_store_ptr(caller_ptr);
+    let contexts = buffer
+        .host_ctx_boxes
+        .values_mut()
+        .map(|v| &mut **v as *mut SampleContext)
+        .collect::<Vec<_>>();
+    // The contexts and the buffer stay alive until the poll returns, even if a callback
+    // closes the object
+    state.enter_poll();
+    // Records left by a stopped poll come first, the buffer isn't polled if the guest
+    // stops again while they are delivered
+    for &context in contexts.iter() {
+        deliver_carried_records(unsafe { &mut *context }, caller);
+    }
+    let run_deadline = caller.data().deadlines.run_deadline();
+    let res = if caller.data().poll_stop_code.is_some() {
+        0
+    } else {
+        let buffer = caller.data().object_map[&program]
+            .buffers
+            .iter()
+            .find(|v| v.contains_map(fd))
+            .unwrap();
+        match timeout_ms {
+            Some(timeout_ms) => buffer.bpf_buffer__poll(run_deadline.wait_timeout_ms(timeout_ms)),
+            None => buffer.bpf_buffer__consume(),
+        }
+    };
+    let mut stop_code = caller.data_mut().poll_stop_code.take().unwrap_or(0);
+    if stop_code == 0 && timeout_ms.is_some() && run_deadline.passed() {
+        debug!("Polling stopped by the timeout of the run");
+        stop_code = -ECANCELED;
+    }
+    // Deliver the records left in the arenas of all maps consumed by the buffer,
+    // they are already consumed from the buffer even if the poll was stopped
+    for context in contexts {
+        let context = unsafe { &mut *context };
+        if !context.batch || context.batch_count == 0 {
+            continue;
+        }
+        if stop_code != 0 {
+            // The guest asked to stop, so they are delivered by the next poll
+            carry_batch(context, caller);
+            continue;
+        }
+        stop_code = flush_batch(context, caller);
+    }
+    caller.data_mut().leave_poll();
+    if stop_code != 0 {
+        // Kept until the next poll for `wasm_bpf_buffer_poll_all`
+        caller.data_mut().poll_stop_code = Some(stop_code);
+        return stop_code;
+    }
     if res < 0 {
-        debug!("Failed to poll: {}", res);
         return res;
     }
     return 0;
 }
 
+/// Move the records packed in the arena in front of the carried records, so they are
+/// delivered first by the next poll. Samples truncated to fit in the arena stay truncated.
+fn carry_batch(ctx: &mut SampleContext, caller: &mut CallerType) {
+    let header_size = std::mem::size_of::<WasmBatchRecord>();
+    let memory = caller.get_memory().expect("Memory must be exported");
+    let mut records = vec![];
+    let mut offset = ctx.raw_wasm_data_buffer as usize;
+    for _ in 0..ctx.batch_count {
+        let record = match caller.read_wasm_struct::<WasmBatchRecord>(offset) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to read wasm memory: {}", e);
+                break;
+            }
+        };
+        let mut data = vec![0u8; record.len as usize];
+        if let Err(e) = memory.read(&mut *caller, offset + header_size, &mut data) {
+            error!("Failed to read wasm memory: {}", e);
+            break;
+        }
+        records.push(CarriedRecord::Sample(record.cpu, data));
+        offset += batch_record_size(record.len as usize);
+    }
+    for record in records.into_iter().rev() {
+        ctx.carried_records.push_front(record);
+    }
+    ctx.batch_offset = 0;
+    ctx.batch_count = 0;
+}
+
+/// Deliver the records carried by `ctx` in order, until all are delivered or the guest
+/// stops the poll
+fn deliver_carried_records(ctx: &mut SampleContext, caller: &mut CallerType) {
+    while caller.data().poll_stop_code.is_none() {
+        match ctx.carried_records.pop_front() {
+            Some(CarriedRecord::Sample(cpu, data)) => {
+                ctx.cpu = cpu;
+                deliver_sample(ctx, caller, &data);
+            }
+            Some(CarriedRecord::Lost(cpu, cnt)) => call_lost_callback(ctx, caller, cpu, cnt),
+            None => break,
+        }
+    }
+}
+
+/// Set the callback of the buffer consuming map `fd` without polling it, to be
+/// used by `wasm_bpf_buffer_poll_all`
+pub fn wasm_bpf_buffer_register(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+) -> i32 {
+    debug!("bpf buffer register");
+    if max_size <= 0 {
+        debug!("Invalid size of the buffer: {}", max_size);
+        return -EINVAL;
+    }
+    ensure_enough_memory!(caller, data, max_size, -EINVAL);
+    setup_context(
+        caller.data_mut(),
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        false,
+        false,
+        false,
+    )
+}
+
+/// Wait up to `timeout_ms` for samples on all buffers with a callback set by
+/// `wasm_bpf_buffer_register` or one of the poll functions, then deliver samples of
+/// the ready buffers to the callbacks of their maps. Returns the number of ready
+/// buffers or a negative error code, -ENOENT if no buffer has a callback, or
+/// -ECANCELED if the timeout of the run passed while waiting. The code which stopped
+/// the poll of a buffer, as returned by `wasm_bpf_buffer_poll`, is written to the i32
+/// at `stop_code`, which is set to zero otherwise.
+pub fn wasm_bpf_buffer_poll_all(
+    mut caller: CallerType,
+    timeout_ms: i32,
+    stop_code: WasmPointer,
+) -> i32 {
+    debug!("bpf buffer poll all");
+    if let Err(err) = caller.write_wasm_struct(stop_code as usize, &0i32) {
+        debug!("Invalid pointer for the stop code: {}", err);
+        return -EINVAL;
+    }
+    // (object, fd of one map of the buffer) of each buffer to wait on
+    let mut targets = vec![];
+    // Indexes in `targets` of the buffers to consume
+    let mut ready = vec![];
+    let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if epoll_fd < 0 {
+        let err = std::io::Error::last_os_error();
+        debug!("Failed to create epoll fd: {}", err);
+        return -err.raw_os_error().unwrap_or(EINVAL);
+    }
+    let epoll_fd = unsafe { OwnedFd::from_raw_fd(epoll_fd) };
+    for (id, object) in caller.data().object_map.iter() {
+        for buffer in object.buffers.iter() {
+            let fd = match buffer.host_ctx_boxes.iter().find(|(_, v)| v.registered) {
+                Some((fd, _)) => *fd,
+                None => continue,
+            };
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: targets.len() as u64,
+            };
+            let res = unsafe {
+                libc::epoll_ctl(
+                    epoll_fd.as_raw_fd(),
+                    libc::EPOLL_CTL_ADD,
+                    buffer.bpf_buffer__epoll_fd(),
+                    &mut event,
+                )
+            };
+            if res < 0 {
+                let err = std::io::Error::last_os_error();
+                debug!("Failed to add buffer of map {} to epoll: {}", fd, err);
+                return -err.raw_os_error().unwrap_or(EINVAL);
+            }
+            // Records carried by a stopped poll are pending even if the buffer isn't readable
+            if buffer.has_carried_records() {
+                ready.push(targets.len());
+            }
+            targets.push((*id, fd));
+        }
+    }
+    if targets.is_empty() {
+        debug!("No buffer has a callback registered");
+        return -ENOENT;
+    }
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; targets.len()];
+    let run_deadline = caller.data().deadlines.run_deadline();
+    let cnt = unsafe {
+        libc::epoll_wait(
+            epoll_fd.as_raw_fd(),
+            events.as_mut_ptr(),
+            events.len() as i32,
+            if ready.is_empty() {
+                run_deadline.wait_timeout_ms(timeout_ms)
+            } else {
+                0
+            },
+        )
+    };
+    if cnt < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return 0;
+        }
+        debug!("Failed to wait on buffers: {}", err);
+        return -err.raw_os_error().unwrap_or(EINVAL);
+    }
+    if cnt == 0 && ready.is_empty() && run_deadline.passed() {
+        debug!("Polling stopped by the timeout of the run");
+        return -ECANCELED;
+    }
+    for event in &events[..cnt as usize] {
+        if !ready.contains(&(event.u64 as usize)) {
+            ready.push(event.u64 as usize);
+        }
+    }
+    for &index in ready.iter() {
+        let (program, fd) = targets[index];
+        if !caller.data().object_map.contains_key(&program) {
+            // Closed by a callback of a previous buffer
+            continue;
+        }
+        let res = consume_buffer(&mut caller, program, fd, None);
+        if let Some(code) = caller.data().poll_stop_code {
+            // Samples of the other ready buffers stay there for the next poll
+            if let Err(err) = caller.write_wasm_struct(stop_code as usize, &code) {
+                debug!("Invalid pointer for the stop code: {}", err);
+                return -EINVAL;
+            }
+            break;
+        }
+        if res < 0 {
+            return res;
+        }
+    }
+    return ready.len() as i32;
+}
+
+/// Same as `wasm_bpf_buffer_poll`, but never waits. Only the samples
+/// already available in the buffer are delivered to the callback. All errors are negative.
+pub fn wasm_bpf_buffer_consume(
+    caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    sample_func: WasmPointer,
+    ctx: WasmPointer,
+    data: WasmPointer,
+    max_size: i32,
+) -> i32 {
+    debug!("bpf buffer consume");
+    buffer_poll(
+        caller,
+        program,
+        fd,
+        sample_func,
+        ctx,
+        data,
+        max_size,
+        None,
+        false,
+        false,
+        false,
+    )
+    .unwrap_or_else(|err| err)
+}
+
+/// Returns 1 if samples are pending in the buffer consuming map `fd`, including the
+/// ones left by a stopped poll, 0 if not, or a negative error code. A ring buffer
+/// map without a callback is checked without being consumed, other buffers are
+/// opened if they're not yet.
+pub fn wasm_bpf_buffer_ready(mut caller: CallerType, program: BpfObjectType, fd: i32) -> i32 {
+    debug!("bpf buffer ready");
+    let state = caller.data_mut();
+    if is_unopened_ringbuf(state, program, fd) {
+        if let Err(err) = state.check_new_consumer(program, fd) {
+            return err;
+        }
+        // The map fd of a ring buffer is readable if it has samples
+        return is_fd_readable(fd);
+    }
+    let res = ensure_buffer_opened(state, program, fd);
+    if res != 0 {
+        return res;
+    }
+    let object = ensure_program_mut_by_state!(state, program);
+    let buffer = object.get_buffer_by_fd_mut(fd).unwrap();
+    if buffer.has_carried_records() {
+        return 1;
+    }
+    // An epoll fd is readable if any of the fds it watches are ready
+    let epoll_fd = buffer.bpf_buffer__epoll_fd();
+    if epoll_fd < 0 {
+        return epoll_fd;
+    }
+    return is_fd_readable(epoll_fd);
+}
+
+/// Set the function called with `(ctx, cpu, lost_count)` when samples of a
+/// perf buffer are lost. `ctx` is the one passed to the poll function.
+/// Pass zero to remove the callback. When the map is polled by the poll wrapper,
+/// the export named by `--lost-callback-export-name` is called instead if the
+/// guest has one.
+pub fn wasm_bpf_buffer_set_lost_callback(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    lost_func: WasmPointer,
+) -> i32 {
+    debug!("bpf buffer set lost callback");
+    let state = caller.data_mut();
+    if is_unopened_ringbuf(state, program, fd) {
+        debug!("Lost callbacks are only supported by perf buffers");
+        return -EINVAL;
+    }
+    let res = ensure_buffer_opened(state, program, fd);
+    if res != 0 {
+        return res;
+    }
+    let object = ensure_program_mut_by_state!(state, program);
+    let buffer = object.get_buffer_by_fd_mut(fd).unwrap();
+    if buffer.map_type != BPF_MAP_TYPE_PERF_EVENT_ARRAY {
+        debug!("Lost callbacks are only supported by perf buffers");
+        return -EINVAL;
+    }
+    buffer
+        .host_ctx_boxes
+        .get_mut(&fd)
+        .unwrap()
+        .lost_callback_index = lost_func;
+    return 0;
+}
+
+/// Set the BUFFER_FLAG_* flags of the buffer consuming map `fd`, which change how
+/// samples are passed to the callback of `wasm_bpf_buffer_poll` and its variants.
+/// Batched polling always reports truncation through the records. Flags set
+/// before the buffer is opened are applied when it's opened.
+pub fn wasm_bpf_buffer_set_flags(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    flags: u32,
+) -> i32 {
+    debug!("bpf buffer set flags");
+    if flags & !BUFFER_FLAGS_ALL != 0 {
+        debug!("Unknown buffer flags: {:#x}", flags);
+        return -EINVAL;
+    }
+    let state = caller.data_mut();
+    let object = ensure_program_mut_by_state!(state, program);
+    if let Some(buffer) = object.get_buffer_by_fd_mut(fd) {
+        buffer.host_ctx_boxes.get_mut(&fd).unwrap().flags = flags;
+        return 0;
+    }
+    if let Err(err) = state.check_new_consumer(program, fd) {
+        return err;
+    }
+    let object = ensure_program_mut_by_state!(state, program);
+    object.pending_buffer_flags.insert(fd, flags);
+    return 0;
+}
+
+/// Set the options of perf buffers opened afterwards. `page_cnt` is the number of pages
+/// for each CPU and must be a power of 2. At most one of `wakeup_events` and
+/// `wakeup_watermark` can be non-zero; Leave both zero for the default behavior
+/// which wakes up the poller on every sample.
+pub fn wasm_bpf_set_perf_buffer_options(
+    mut caller: CallerType,
+    page_cnt: u32,
+    wakeup_events: u32,
+    wakeup_watermark: u32,
+) -> i32 {
+    debug!("set perf buffer options");
+    let options = PerfBufferOptions {
+        page_cnt: page_cnt as usize,
+        wakeup_events,
+        wakeup_watermark,
+    };
+    if let Err(err) = options.validate() {
+        debug!("Invalid perf buffer options: {}", err);
+        return -EINVAL;
+    }
+    caller.data_mut().perf_buffer_options = options;
+    return 0;
+}
+
+/// Fill the sample counters of the buffer or buffer file consuming map `fd`
+pub fn wasm_bpf_buffer_stats(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    stats: WasmPointer,
+) -> i32 {
+    debug!("bpf buffer stats");
+    let state = caller.data_mut();
+    let object = ensure_program_mut_by_state!(state, program);
+    let result = match object.get_buffer_by_fd_mut(fd) {
+        Some(buffer) => {
+            let context = &buffer.host_ctx_boxes[&fd];
+            Some(WasmBufferStats {
+                samples: context.samples,
+                lost_samples: context.lost_samples,
+            })
+        }
+        None => object.buffer_files.stats(fd),
+    };
+    #[cfg(feature = "async")]
+    let result = result.or_else(|| object.async_buffers.get(&fd).map(|v| v.stats()));
+    let result = match result {
+        Some(v) => v,
+        None => {
+            debug!("No buffer opened for map fd {}", fd);
+            return -ENOENT;
+        }
+    };
+    if let Err(err) = caller.write_wasm_struct(stats as usize, &result) {
+        debug!("Invalid pointer for stats: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
+
 pub enum BufferInnerType {
     PerfBuf(*mut perf_buffer),
     RingBuffer(*mut ring_buffer),
@@ -162,11 +1244,11 @@ impl BufferInnerType {
 pub struct BpfBuffer {
     pub events: *mut bpf_map,
     pub inner: BufferInnerType,
-    // pub host_ctx: *mut c_void,
     pub map_type: bpf_map_type,
     pub host_sample_fn: Option<SampleCallbackWrapper>,
-    pub wasm_sample_fn: u32,
-    pub host_ctx_box: Option<Box<SampleContext>>,
+    pub perf_options: PerfBufferOptions,
+    // One context for each map consumed by this buffer, indexed by the map fd
+    pub host_ctx_boxes: HashMap<i32, Box<SampleContext>>,
 }
 #[allow(non_snake_case)]
 impl BpfBuffer {
@@ -183,33 +1265,39 @@ impl BpfBuffer {
         Self {
             events,
             inner: BufferInnerType::None,
-            host_ctx_box: None,
+            host_ctx_boxes: HashMap::new(),
             map_type: ty,
             host_sample_fn: None,
-            wasm_sample_fn: 0,
+            perf_options: Default::default(),
         }
     }
+    /// Put `host_ctx` in a box owned by the buffer, returns the pointer passed to libbpf
+    fn insert_context(&mut self, fd: i32, host_ctx: SampleContext) -> *mut c_void {
+        // The boxed context won't move even if the map reallocates
+        self.host_ctx_boxes.insert(fd, Box::new(host_ctx));
+        let ctx_box = &self.host_ctx_boxes[&fd];
+        &**ctx_box as *const SampleContext as *mut c_void
+    }
     pub fn bpf_buffer__open(
         &mut self,
         sample_callback_wrapper: SampleCallbackWrapper,
         host_ctx: SampleContext,
     ) -> i32 {
-        self.host_ctx_box = Some(Box::new(host_ctx));
-        let ctx_ptr = self
-            .host_ctx_box
-            .as_ref()
-            .map(|v| &**v as *const SampleContext as *mut c_void)
-            .unwrap();
         let fd = unsafe { bpf_map__fd(self.events) };
+        let ctx_ptr = self.insert_context(fd, host_ctx);
         let inner = match self.map_type {
             BPF_MAP_TYPE_PERF_EVENT_ARRAY => {
                 self.host_sample_fn = Some(sample_callback_wrapper);
+                let opts = &self.perf_options;
+                // Always use raw perf event attrs, so that custom wakeup settings can be
+                // applied and records read after a callback stops the poll can be carried
+                let mut attr = opts.build_perf_event_attr();
                 BufferInnerType::PerfBuf(unsafe {
-                    perf_buffer__new(
+                    perf_buffer__new_raw(
                         fd,
-                        PERF_BUFFER_PAGES as _,
-                        Some(perfbuf_sample_fn),
-                        None,
+                        opts.page_cnt as _,
+                        &mut attr,
+                        Some(perfbuf_event_fn),
                         ctx_ptr,
                         null(),
                     )
@@ -223,11 +1311,80 @@ impl BpfBuffer {
             }
         };
         if inner.inner_ptr().is_null() {
+            self.host_ctx_boxes.remove(&fd);
             return -1;
         }
         self.inner = inner;
         return 0;
     }
+    /// Open a buffer of the map `fd` which passes samples to `sink` directly, instead of
+    /// the callbacks of the guest. `sink` must outlive the buffer and not move.
+    /// Errors are returned as negative error codes.
+    pub fn bpf_buffer__open_raw<S: SampleSink>(
+        fd: i32,
+        perf_options: PerfBufferOptions,
+        sink: *mut S,
+    ) -> Result<Self, i32> {
+        let map_type = query_map_info(fd)?.type_;
+        let ctx = sink as *mut c_void;
+        let inner = match map_type {
+            BPF_MAP_TYPE_RINGBUF => BufferInnerType::RingBuffer(unsafe {
+                ring_buffer__new(fd, Some(sink_ringbuf_sample_fn::<S>), ctx, null())
+            }),
+            BPF_MAP_TYPE_PERF_EVENT_ARRAY => {
+                let mut attr = perf_options.build_perf_event_attr();
+                BufferInnerType::PerfBuf(unsafe {
+                    perf_buffer__new_raw(
+                        fd,
+                        perf_options.page_cnt as _,
+                        &mut attr,
+                        Some(sink_perfbuf_event_fn::<S>),
+                        ctx,
+                        null(),
+                    )
+                })
+            }
+            ty => {
+                debug!(
+                    "Map {} is not a ring buffer or perf buffer, type={}",
+                    fd, ty
+                );
+                return Err(-EINVAL);
+            }
+        };
+        if inner.inner_ptr().is_null() {
+            let err = std::io::Error::last_os_error();
+            debug!("Failed to open buffer for map fd {}: {}", fd, err);
+            return Err(-err.raw_os_error().unwrap_or(EINVAL));
+        }
+        Ok(Self {
+            events: null_mut(),
+            inner,
+            map_type,
+            host_sample_fn: None,
+            perf_options,
+            host_ctx_boxes: HashMap::new(),
+        })
+    }
+    /// Add another ring buffer map to an opened ring buffer
+    pub fn bpf_buffer__add(
+        &mut self,
+        events: *mut bpf_map,
+        sample_callback_wrapper: SampleCallbackWrapper,
+        host_ctx: SampleContext,
+    ) -> i32 {
+        let rb = match self.inner {
+            BufferInnerType::RingBuffer(s) => s,
+            _ => return -EINVAL,
+        };
+        let fd = unsafe { bpf_map__fd(events) };
+        let ctx_ptr = self.insert_context(fd, host_ctx);
+        let res = unsafe { ring_buffer__add(rb, fd, Some(sample_callback_wrapper), ctx_ptr) };
+        if res != 0 {
+            self.host_ctx_boxes.remove(&fd);
+        }
+        return res;
+    }
     pub fn bpf_buffer__poll(&self, timeout_ms: i32) -> i32 {
         match self.inner {
             BufferInnerType::PerfBuf(s) => unsafe { perf_buffer__poll(s, timeout_ms) },
@@ -235,6 +1392,34 @@ impl BpfBuffer {
             BufferInnerType::None => EINVAL,
         }
     }
+    pub fn bpf_buffer__consume(&self) -> i32 {
+        match self.inner {
+            BufferInnerType::PerfBuf(s) => unsafe { perf_buffer__consume(s) },
+            BufferInnerType::RingBuffer(s) => unsafe { ring_buffer__consume(s) },
+            BufferInnerType::None => EINVAL,
+        }
+    }
+    pub fn bpf_buffer__epoll_fd(&self) -> i32 {
+        match self.inner {
+            BufferInnerType::PerfBuf(s) => unsafe { perf_buffer__epoll_fd(s) },
+            BufferInnerType::RingBuffer(s) => unsafe { ring_buffer__epoll_fd(s) },
+            BufferInnerType::None => -EINVAL,
+        }
+    }
+    /// Whether a stopped poll left records to deliver first by the next poll
+    pub fn has_carried_records(&self) -> bool {
+        self.host_ctx_boxes
+            .values()
+            .any(|v| !v.carried_records.is_empty())
+    }
+    pub fn contains_map(&self, fd: i32) -> bool {
+        self.host_ctx_boxes.contains_key(&fd)
+    }
+    pub fn set_store_ptr(&mut self, store_ptr: *mut CallerType<'static>) {
+        for ctx in self.host_ctx_boxes.values_mut() {
+            ctx.store_ptr = store_ptr;
+        }
+    }
 }
 
 impl Drop for BpfBuffer {
@@ -244,11 +1429,292 @@ impl Drop for BpfBuffer {
                 perf_buffer__free(s);
             },
             BufferInnerType::RingBuffer(s) => unsafe { ring_buffer__free(s) },
-            BufferInnerType::None => todo!(),
+            BufferInnerType::None => {}
         }
     }
 }
 
-extern "C" fn perfbuf_sample_fn(ctx: *mut c_void, _cpu: i32, data: *mut c_void, size: u32) {
+fn perfbuf_sample_fn(ctx: *mut c_void, cpu: i32, data: *mut c_void, size: u32) {
+    unsafe { (*(ctx as *mut SampleContext)).cpu = cpu };
     sample_function_wrapper(ctx, data, size as u64);
 }
+
+/// Dispatch the records of perf buffers opened with raw attrs
+extern "C" fn perfbuf_event_fn(
+    ctx: *mut c_void,
+    cpu: i32,
+    event: *mut perf_event_header,
+) -> bpf_perf_event_ret {
+    // libbpf consumes the record whatever is returned, and DONE only moves on to the
+    // next CPU. So once the poll is stopped, keep the record for the next poll.
+    if is_poll_stopped(ctx) {
+        let ctx = unsafe { &mut *(ctx as *mut SampleContext) };
+        match unsafe { PerfRecord::parse(event) } {
+            PerfRecord::Sample(data) => {
+                ctx.samples += 1;
+                ctx.carried_records
+                    .push_back(CarriedRecord::Sample(cpu, data.to_vec()));
+            }
+            PerfRecord::Lost(lost) => {
+                ctx.lost_samples += lost;
+                ctx.carried_records
+                    .push_back(CarriedRecord::Lost(cpu, lost));
+            }
+            PerfRecord::Unknown(_) => {}
+        }
+        return LIBBPF_PERF_EVENT_DONE;
+    }
+    match unsafe { PerfRecord::parse(event) } {
+        PerfRecord::Sample(data) => {
+            perfbuf_sample_fn(ctx, cpu, data.as_ptr() as *mut c_void, data.len() as u32);
+        }
+        PerfRecord::Lost(lost) => perfbuf_lost_fn(ctx, cpu, lost),
+        PerfRecord::Unknown(ty) => {
+            debug!("Unknown perf event type: {}", ty);
+        }
+    }
+    LIBBPF_PERF_EVENT_CONT
+}
+
+/// Host side consumer of the samples of a buffer opened by `bpf_buffer__open_raw`
+pub trait SampleSink {
+    /// Take a sample produced by `cpu`, -1 for ring buffers
+    fn push(&mut self, cpu: i32, data: &[u8]);
+    /// Called when `cnt` samples are lost by a perf buffer on `cpu`
+    fn lost(&mut self, cpu: i32, cnt: u64) {
+        debug!("{} samples lost on cpu {}", cnt, cpu);
+    }
+    /// Whether to stop consuming the buffer, the other samples then stay in it
+    fn is_full(&self) -> bool {
+        false
+    }
+}
+
+extern "C" fn sink_ringbuf_sample_fn<S: SampleSink>(
+    ctx: *mut c_void,
+    data: *mut c_void,
+    size: u64,
+) -> i32 {
+    let sink = unsafe { &mut *(ctx as *mut S) };
+    sink.push(-1, unsafe {
+        from_raw_parts(data as *const u8, size as usize)
+    });
+    if sink.is_full() {
+        return STOP_POLLING;
+    }
+    0
+}
+
+extern "C" fn sink_perfbuf_event_fn<S: SampleSink>(
+    ctx: *mut c_void,
+    cpu: i32,
+    event: *mut perf_event_header,
+) -> bpf_perf_event_ret {
+    let sink = unsafe { &mut *(ctx as *mut S) };
+    match unsafe { PerfRecord::parse(event) } {
+        PerfRecord::Sample(data) => sink.push(cpu, data),
+        PerfRecord::Lost(lost) => sink.lost(cpu, lost),
+        PerfRecord::Unknown(ty) => debug!("Unknown perf event type: {}", ty),
+    }
+    // Stops consuming the buffer of this CPU, the first record of each other CPU is
+    // still consumed and taken by the sink
+    if sink.is_full() {
+        return LIBBPF_PERF_EVENT_DONE;
+    }
+    LIBBPF_PERF_EVENT_CONT
+}
+
+/// A record read from a perf buffer opened with raw attrs
+#[derive(Debug, PartialEq, Eq)]
+pub enum PerfRecord<'a> {
+    Sample(&'a [u8]),
+    // Number of samples lost
+    Lost(u64),
+    Unknown(u32),
+}
+
+impl PerfRecord<'_> {
+    pub unsafe fn parse(event: *const perf_event_header) -> Self {
+        let header = &*event;
+        let body = (event as *const u8).add(std::mem::size_of::<perf_event_header>());
+        match header.type_ {
+            PERF_RECORD_SAMPLE => {
+                // struct { struct perf_event_header header; u32 size; char data[size]; }
+                let size = (body as *const u32).read_unaligned();
+                let data = body.add(std::mem::size_of::<u32>());
+                PerfRecord::Sample(from_raw_parts(data, size as usize))
+            }
+            PERF_RECORD_LOST => {
+                // struct { struct perf_event_header header; u64 id; u64 lost; }
+                PerfRecord::Lost((body as *const u64).add(1).read_unaligned())
+            }
+            ty => PerfRecord::Unknown(ty),
+        }
+    }
+}
+
+fn is_poll_stopped(ctx: *mut c_void) -> bool {
+    let ctx = unsafe { &*(ctx as *mut SampleContext) };
+    let caller = unsafe { &*ctx.store_ptr };
+    caller.data().poll_stop_code.is_some()
+}
+
+fn perfbuf_lost_fn(ctx: *mut c_void, cpu: i32, cnt: u64) {
+    let ctx = unsafe { &mut *(ctx as *mut SampleContext) };
+    let caller = unsafe { &mut *ctx.store_ptr };
+    debug!("{} samples lost on cpu {}", cnt, cpu);
+    ctx.lost_samples += cnt;
+    call_lost_callback(ctx, caller, cpu, cnt);
+}
+
+fn call_lost_callback(ctx: &mut SampleContext, caller: &mut CallerType, cpu: i32, cnt: u64) {
+    let func = match find_lost_callback(caller, ctx.via_wrapper, ctx.lost_callback_index) {
+        Some(v) => v,
+        None => return,
+    };
+    let params = lost_callback_params(ctx.wasm_ctx, cpu, cnt);
+    if let Err(e) = call_callback(caller, |caller| func.call(caller, &params, &mut [])) {
+        error!("Failed to call the lost callback: {}", e);
+    }
+}
+
+/// Find the lost callback, falling back to the table if the guest has no lost
+/// callback export. None if there's no callback to call.
+pub(super) fn find_lost_callback(
+    caller: &mut CallerType,
+    via_wrapper: bool,
+    index: u32,
+) -> Option<Func> {
+    if via_wrapper {
+        if let Ok(v) = find_callback(caller, CallbackKind::Lost, true, index) {
+            return Some(v);
+        }
+    }
+    if index == 0 {
+        return None;
+    }
+    find_callback(caller, CallbackKind::Lost, false, index).ok()
+}
+
+/// Parameters of a lost callback, `(ctx, cpu, lost_count)`
+pub(super) fn lost_callback_params(ctx: u32, cpu: i32, cnt: u64) -> [Val; 3] {
+    [Val::I32(ctx as _), Val::I32(cpu), Val::I64(cnt as _)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::func::EBUSY;
+
+    fn options(page_cnt: usize, wakeup_events: u32, wakeup_watermark: u32) -> PerfBufferOptions {
+        PerfBufferOptions {
+            page_cnt,
+            wakeup_events,
+            wakeup_watermark,
+        }
+    }
+
+    #[test]
+    fn page_count_must_be_a_power_of_two() {
+        assert!(options(1, 0, 0).validate().is_ok());
+        assert!(options(64, 0, 0).validate().is_ok());
+        assert!(options(0, 0, 0).validate().is_err());
+        assert!(options(3, 0, 0).validate().is_err());
+    }
+
+    #[test]
+    fn only_one_wakeup_setting_is_allowed() {
+        assert!(options(8, 16, 0).validate().is_ok());
+        assert!(options(8, 0, 4096).validate().is_ok());
+        assert!(options(8, 16, 4096).validate().is_err());
+    }
+
+    #[test]
+    fn legacy_poll_errors_keep_the_positive_codes() {
+        assert_eq!(legacy_poll_error(-EINVAL), EINVAL);
+        assert_eq!(legacy_poll_error(-ENOENT), ENOENT);
+        assert_eq!(legacy_poll_error(-1), -1);
+        assert_eq!(legacy_poll_error(-EBUSY), -EBUSY);
+    }
+
+    #[test]
+    fn perf_event_attr_follows_the_wakeup_setting() {
+        let attr = options(8, 0, 0).build_perf_event_attr();
+        assert_eq!(attr.watermark(), 0);
+        assert_eq!(unsafe { attr.__bindgen_anon_2.wakeup_events }, 1);
+        let attr = options(8, 16, 0).build_perf_event_attr();
+        assert_eq!(attr.watermark(), 0);
+        assert_eq!(unsafe { attr.__bindgen_anon_2.wakeup_events }, 16);
+        let attr = options(8, 0, 4096).build_perf_event_attr();
+        assert_eq!(attr.watermark(), 1);
+        assert_eq!(unsafe { attr.__bindgen_anon_2.wakeup_watermark }, 4096);
+    }
+
+    #[test]
+    fn batch_records_are_aligned_to_8_bytes() {
+        assert_eq!(batch_record_size(0), 16);
+        assert_eq!(batch_record_size(1), 24);
+        assert_eq!(batch_record_size(8), 24);
+        assert_eq!(batch_record_size(9), 32);
+    }
+
+    #[test]
+    fn batch_records_are_truncated_to_the_end_of_the_arena() {
+        let record = batch_record(0, 64, 3, 20);
+        assert_eq!((record.len, record.size, record.cpu), (20, 20, 3));
+        let record = batch_record(40, 64, -1, 20);
+        assert_eq!((record.len, record.size, record.cpu), (8, 20, -1));
+        let record = batch_record(56, 64, 0, 4);
+        assert_eq!((record.len, record.size), (0, 4));
+    }
+
+    #[test]
+    fn truncated_samples_are_marked_with_the_flag() {
+        assert_eq!(sample_size(100, 40, 0), 100);
+        assert_eq!(sample_size(100, 40, BUFFER_FLAG_ALLOC_SAMPLES), 100);
+        assert_eq!(
+            sample_size(100, 40, BUFFER_FLAG_MARK_TRUNCATED),
+            40 | SAMPLE_TRUNCATED_BIT
+        );
+        assert_eq!(sample_size(40, 40, BUFFER_FLAG_MARK_TRUNCATED), 40);
+    }
+
+    /// A perf event record of type `ty`, aligned like the records of a perf buffer
+    fn perf_record(ty: u32, body: &[u8]) -> Vec<u64> {
+        let header_size = std::mem::size_of::<perf_event_header>();
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&ty.to_ne_bytes());
+        bytes.extend_from_slice(&0u16.to_ne_bytes());
+        bytes.extend_from_slice(&((header_size + body.len()) as u16).to_ne_bytes());
+        bytes.extend_from_slice(body);
+        let mut record = vec![0u64; bytes.len().div_ceil(8)];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                record.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            )
+        };
+        record
+    }
+
+    #[test]
+    fn perf_records_are_parsed() {
+        let mut body = 5u32.to_ne_bytes().to_vec();
+        body.extend_from_slice(b"hello");
+        let record = perf_record(PERF_RECORD_SAMPLE, &body);
+        let event = record.as_ptr() as *const perf_event_header;
+        assert_eq!(
+            unsafe { PerfRecord::parse(event) },
+            PerfRecord::Sample(b"hello")
+        );
+        let mut body = 7u64.to_ne_bytes().to_vec();
+        body.extend_from_slice(&42u64.to_ne_bytes());
+        let record = perf_record(PERF_RECORD_LOST, &body);
+        let event = record.as_ptr() as *const perf_event_header;
+        assert_eq!(unsafe { PerfRecord::parse(event) }, PerfRecord::Lost(42));
+        let record = perf_record(99, &[]);
+        let event = record.as_ptr() as *const perf_event_header;
+        assert_eq!(unsafe { PerfRecord::parse(event) }, PerfRecord::Unknown(99));
+    }
+}
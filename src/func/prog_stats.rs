@@ -0,0 +1,69 @@
+use std::ffi::c_void;
+
+use libbpf_rs::libbpf_sys::{bpf_obj_get_info_by_fd, bpf_prog_info};
+use log::debug;
+
+use crate::{
+    ensure_c_str, ensure_program_mut_by_caller,
+    func::{EINVAL, ENOENT},
+    state::CallerType,
+    utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer, WasmString};
+
+/// The struct filled for the guest by `wasm_bpf_prog_stats`.
+/// `run_cnt` and `run_time_ns` are only counted while BPF stats are enabled,
+/// see the `--enable-stats` option of the runtime.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WasmProgStats {
+    pub run_cnt: u64,
+    pub run_time_ns: u64,
+    pub recursion_misses: u64,
+    pub verified_insns: u32,
+    pub jited_prog_len: u32,
+}
+
+/// Query the runtime statistics of the program named `name`
+pub fn wasm_bpf_prog_stats(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    stats: WasmPointer,
+) -> i32 {
+    debug!("prog stats");
+    let prog_name = ensure_c_str!(caller, name);
+    let object = ensure_program_mut_by_caller!(caller, program);
+    let prog_fd = match object.get_object().prog(&prog_name) {
+        Some(v) => v.fd(),
+        None => {
+            debug!("No program named `{}` found", prog_name);
+            return -ENOENT;
+        }
+    };
+    let mut info = bpf_prog_info::default();
+    let mut info_len = std::mem::size_of::<bpf_prog_info>() as u32;
+    let ret_val = unsafe {
+        bpf_obj_get_info_by_fd(prog_fd, &mut info as *mut _ as *mut c_void, &mut info_len)
+    };
+    if ret_val != 0 {
+        debug!(
+            "Failed to query info of program `{}`: {}",
+            prog_name, ret_val
+        );
+        return ret_val;
+    }
+    let result = WasmProgStats {
+        run_cnt: info.run_cnt,
+        run_time_ns: info.run_time_ns,
+        recursion_misses: info.recursion_misses,
+        verified_insns: info.verified_insns,
+        jited_prog_len: info.jited_prog_len,
+    };
+    if let Err(err) = caller.write_wasm_struct(stats as usize, &result) {
+        debug!("Invalid pointer for stats: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
@@ -0,0 +1,506 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+
+use crate::{
+    deadline::RunDeadline,
+    ensure_enough_memory, ensure_program_mut_by_state,
+    func::{EAGAIN, ECANCELED, EINTR, EINVAL, ENOENT},
+    shutdown::is_shutdown_requested,
+    state::CallerType,
+    utils::CallerUtils,
+};
+
+use super::{
+    poll::{BpfBuffer, PerfBufferOptions, SampleSink},
+    BpfObjectType, WasmPointer,
+};
+
+// How often the polling thread checks whether it should exit
+const POLL_INTERVAL_MS: i32 = 100;
+
+/// What to do with a new sample if the queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    // Stop draining the BPF buffer until the guest pops a sample
+    Block,
+}
+
+impl OverflowPolicy {
+    pub fn from_raw(policy: u32) -> Option<Self> {
+        match policy {
+            0 => Some(Self::DropOldest),
+            1 => Some(Self::DropNewest),
+            2 => Some(Self::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Counters of a queue filled for the guest by `wasm_bpf_buffer_queue_stats`
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WasmQueueStats {
+    // Samples waiting in the queue
+    pub queued: u64,
+    // Samples drained from the BPF buffer
+    pub samples: u64,
+    // Samples dropped because the queue was full
+    pub dropped_samples: u64,
+    // Samples lost by perf buffers before reaching the queue
+    pub lost_samples: u64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    samples: VecDeque<Vec<u8>>,
+    stats: WasmQueueStats,
+}
+
+struct SharedQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    depth: usize,
+    policy: OverflowPolicy,
+    stopped: AtomicBool,
+}
+
+impl SharedQueue {
+    fn push(&self, sample: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.samples += 1;
+        while state.samples.len() >= self.depth {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.samples.pop_front();
+                    state.stats.dropped_samples += 1;
+                }
+                OverflowPolicy::DropNewest => {
+                    state.stats.dropped_samples += 1;
+                    return;
+                }
+                OverflowPolicy::Block => {
+                    if self.stopped.load(Ordering::Relaxed) {
+                        state.stats.dropped_samples += 1;
+                        return;
+                    }
+                    state = self
+                        .not_full
+                        .wait_timeout(state, Duration::from_millis(POLL_INTERVAL_MS as u64))
+                        .unwrap()
+                        .0;
+                }
+            }
+        }
+        state.samples.push_back(sample);
+        self.not_empty.notify_one();
+    }
+}
+
+/// A BPF buffer drained by a dedicated thread into a bounded queue, which the
+/// guest pulls samples from
+pub struct SampleQueue {
+    shared: Arc<SharedQueue>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SampleQueue {
+    /// Start draining the ring buffer or perf buffer map `map_fd`, errors are
+    /// returned as negative error codes
+    pub fn start(
+        map_fd: i32,
+        depth: usize,
+        policy: OverflowPolicy,
+        perf_options: PerfBufferOptions,
+    ) -> Result<Self, i32> {
+        let shared = Arc::new(SharedQueue {
+            state: Default::default(),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            depth,
+            policy,
+            stopped: AtomicBool::new(false),
+        });
+        let thread_shared = shared.clone();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name(format!("bpf-queue-{}", map_fd))
+            .spawn(move || poll_thread(map_fd, perf_options, thread_shared, ready_tx))
+            .map_err(|e| -e.raw_os_error().unwrap_or(EINVAL))?;
+        match ready_rx.recv() {
+            Ok(0) => Ok(Self {
+                shared,
+                thread: Some(thread),
+            }),
+            Ok(err) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => {
+                let _ = thread.join();
+                Err(-EINVAL)
+            }
+        }
+    }
+    /// Pop the oldest sample, waiting up to `timeout` or forever if the queue is empty,
+    /// unless a shutdown is requested
+    pub fn pop(&self, timeout: Option<Duration>) -> Option<Vec<u8>> {
+        self.pop_until(timeout, is_shutdown_requested)
+    }
+    /// Same as `pop`, but the wait ends as soon as `stop` returns true
+    fn pop_until(&self, timeout: Option<Duration>, stop: impl Fn() -> bool) -> Option<Vec<u8>> {
+        let deadline = timeout.map(|v| Instant::now() + v);
+        let mut state = self.shared.state.lock().unwrap();
+        while state.samples.is_empty() && !stop() {
+            // Wake up regularly so a stop isn't blocked by an empty queue
+            let mut wait = Duration::from_millis(POLL_INTERVAL_MS as u64);
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                wait = wait.min(left);
+            }
+            state = self
+                .shared
+                .not_empty
+                .wait_timeout_while(state, wait, |v| v.samples.is_empty() && !stop())
+                .unwrap()
+                .0;
+        }
+        let sample = state.samples.pop_front();
+        if sample.is_some() {
+            self.shared.not_full.notify_one();
+        }
+        sample
+    }
+    pub fn stats(&self) -> WasmQueueStats {
+        let state = self.shared.state.lock().unwrap();
+        WasmQueueStats {
+            queued: state.samples.len() as u64,
+            ..state.stats
+        }
+    }
+}
+
+impl Drop for SampleQueue {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        self.shared.not_full.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The buffer is created on the polling thread, which is the only user of it
+fn poll_thread(
+    map_fd: i32,
+    perf_options: PerfBufferOptions,
+    shared: Arc<SharedQueue>,
+    ready: mpsc::Sender<i32>,
+) {
+    let mut sink = &*shared;
+    let buffer = match BpfBuffer::bpf_buffer__open_raw(map_fd, perf_options, &mut sink) {
+        Ok(v) => v,
+        Err(err) => {
+            let _ = ready.send(err);
+            return;
+        }
+    };
+    let _ = ready.send(0);
+    while !shared.stopped.load(Ordering::Relaxed) {
+        let res = buffer.bpf_buffer__poll(POLL_INTERVAL_MS);
+        if res < 0 && res != -EINTR {
+            error!("Failed to poll buffer of map fd {}: {}", map_fd, res);
+            break;
+        }
+    }
+}
+
+// The queue is shared with the guest, so the polling thread pushes through a reference
+impl SampleSink for &SharedQueue {
+    fn push(&mut self, _cpu: i32, data: &[u8]) {
+        SharedQueue::push(self, data.to_vec());
+    }
+    fn lost(&mut self, _cpu: i32, cnt: u64) {
+        self.state.lock().unwrap().stats.lost_samples += cnt;
+    }
+}
+
+/// Drain the ring buffer or perf buffer map `fd` of `program` on a host thread into a
+/// queue holding up to `depth` samples. `policy` is 0 to drop the oldest samples if the
+/// queue is full, 1 to drop the newest ones, or 2 to stop draining until the guest pops.
+/// The map can't be polled by `wasm_bpf_buffer_poll` and its variants in the meantime.
+pub fn wasm_bpf_buffer_queue_start(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    depth: u32,
+    policy: u32,
+) -> i32 {
+    debug!("bpf buffer queue start");
+    let policy = match OverflowPolicy::from_raw(policy) {
+        Some(v) => v,
+        None => {
+            debug!("Invalid overflow policy: {}", policy);
+            return -EINVAL;
+        }
+    };
+    if depth == 0 {
+        debug!("Queue depth must be positive");
+        return -EINVAL;
+    }
+    let state = caller.data_mut();
+    if let Err(err) = state.check_new_consumer(program, fd) {
+        return err;
+    }
+    let perf_options = state.perf_buffer_options;
+    let object = ensure_program_mut_by_state!(state, program);
+    match SampleQueue::start(fd, depth as usize, policy, perf_options) {
+        Ok(queue) => {
+            object.queues.insert(fd, queue);
+            0
+        }
+        Err(err) => {
+            debug!("Failed to start queue for map fd {}: {}", fd, err);
+            err
+        }
+    }
+}
+
+/// Pop the oldest sample of the queue of map `fd` into `data`, waiting up to
+/// `timeout_ms` if the queue is empty, or forever if it's negative. Returns the size
+/// of the sample, which is truncated if it's larger than `max_size`, -EAGAIN if no
+/// sample is available, -ECANCELED if the timeout of the run passed while waiting,
+/// or a negative error code.
+pub fn wasm_bpf_buffer_queue_pop(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    data: WasmPointer,
+    max_size: i32,
+    timeout_ms: i32,
+) -> i32 {
+    debug!("bpf buffer queue pop");
+    if max_size <= 0 {
+        debug!("Invalid size of the buffer: {}", max_size);
+        return -EINVAL;
+    }
+    ensure_enough_memory!(caller, data, max_size, -EINVAL);
+    let run_deadline = caller.data().deadlines.run_deadline();
+    let object = ensure_program_mut_by_state!(caller.data_mut(), program);
+    let queue = match object.queues.get(&fd) {
+        Some(v) => v,
+        None => {
+            debug!("No queue started for map fd {}", fd);
+            return -ENOENT;
+        }
+    };
+    let timeout = (timeout_ms >= 0).then(|| Duration::from_millis(timeout_ms as u64));
+    let sample = match pop_within(queue, run_deadline, timeout) {
+        Ok(v) => v,
+        Err(err) => return err,
+    };
+    let len = sample.len().min(max_size as usize);
+    let memory = caller.get_memory().expect("Memory must be exported");
+    if let Err(e) = memory.write(&mut caller, data as usize, &sample[..len]) {
+        error!("Failed to write wasm memory: {}", e);
+        return -EINVAL;
+    }
+    return sample.len() as i32;
+}
+
+/// Pop a sample of `queue` waiting up to `timeout` but not past `run_deadline`.
+/// Returns -EAGAIN if no sample came in time, or -ECANCELED if the deadline passed.
+fn pop_within(
+    queue: &SampleQueue,
+    run_deadline: RunDeadline,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>, i32> {
+    match queue.pop(run_deadline.wait_timeout(timeout)) {
+        Some(v) => Ok(v),
+        None if run_deadline.passed() => {
+            debug!("Waiting for samples stopped by the timeout of the run");
+            Err(-ECANCELED)
+        }
+        None => Err(-EAGAIN),
+    }
+}
+
+/// Fill the counters of the queue of map `fd`
+pub fn wasm_bpf_buffer_queue_stats(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    fd: i32,
+    stats: WasmPointer,
+) -> i32 {
+    debug!("bpf buffer queue stats");
+    let object = ensure_program_mut_by_state!(caller.data_mut(), program);
+    let result = match object.queues.get(&fd) {
+        Some(v) => v.stats(),
+        None => {
+            debug!("No queue started for map fd {}", fd);
+            return -ENOENT;
+        }
+    };
+    if let Err(err) = caller.write_wasm_struct(stats as usize, &result) {
+        debug!("Invalid pointer for stats: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
+
+/// Stop draining map `fd` and drop the samples left in its queue
+pub fn wasm_bpf_buffer_queue_stop(mut caller: CallerType, program: BpfObjectType, fd: i32) -> i32 {
+    debug!("bpf buffer queue stop");
+    let object = ensure_program_mut_by_state!(caller.data_mut(), program);
+    match object.queues.remove(&fd) {
+        Some(_) => 0,
+        None => {
+            debug!("No queue started for map fd {}", fd);
+            -ENOENT
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadline::Deadlines;
+
+    fn new_queue(depth: usize, policy: OverflowPolicy) -> Arc<SharedQueue> {
+        Arc::new(SharedQueue {
+            state: Default::default(),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            depth,
+            policy,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    fn queued(queue: &SharedQueue) -> Vec<Vec<u8>> {
+        queue
+            .state
+            .lock()
+            .unwrap()
+            .samples
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_samples() {
+        let queue = new_queue(2, OverflowPolicy::DropOldest);
+        for sample in 1..=3u8 {
+            queue.push(vec![sample]);
+        }
+        assert_eq!(queued(&queue), vec![vec![2], vec![3]]);
+        let stats = queue.state.lock().unwrap().stats;
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.dropped_samples, 1);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_oldest_samples() {
+        let queue = new_queue(2, OverflowPolicy::DropNewest);
+        for sample in 1..=3u8 {
+            queue.push(vec![sample]);
+        }
+        assert_eq!(queued(&queue), vec![vec![1], vec![2]]);
+        let stats = queue.state.lock().unwrap().stats;
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.dropped_samples, 1);
+    }
+
+    #[test]
+    fn block_waits_until_a_sample_is_popped() {
+        let queue = new_queue(1, OverflowPolicy::Block);
+        queue.push(vec![1]);
+        let pusher = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.push(vec![2]))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(queued(&queue), vec![vec![1]]);
+        queue.state.lock().unwrap().samples.pop_front();
+        queue.not_full.notify_one();
+        pusher.join().unwrap();
+        assert_eq!(queued(&queue), vec![vec![2]]);
+        let stats = queue.state.lock().unwrap().stats;
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.dropped_samples, 0);
+    }
+
+    #[test]
+    fn pop_stops_waiting_on_shutdown() {
+        let queue = SampleQueue {
+            shared: new_queue(1, OverflowPolicy::DropOldest),
+            thread: None,
+        };
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let requester = {
+            let shutdown = shutdown.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                shutdown.store(true, Ordering::Relaxed);
+            })
+        };
+        let start = Instant::now();
+        let sample = queue.pop_until(Some(Duration::from_secs(60)), || {
+            shutdown.load(Ordering::Relaxed)
+        });
+        requester.join().unwrap();
+        assert_eq!(sample, None);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn pop_stops_waiting_at_the_run_deadline() {
+        let queue = SampleQueue {
+            shared: new_queue(1, OverflowPolicy::DropOldest),
+            thread: None,
+        };
+        let mut deadlines = Deadlines::default();
+        deadlines.limits.timeout = Some(Duration::from_millis(50));
+        deadlines.start_run();
+        let start = Instant::now();
+        // Waits forever without the timeout of the run
+        let res = pop_within(&queue, deadlines.run_deadline(), None);
+        assert_eq!(res, Err(-ECANCELED));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        // A shorter timeout of the guest still ends with -EAGAIN
+        deadlines.limits.timeout = Some(Duration::from_secs(60));
+        deadlines.start_run();
+        let res = pop_within(
+            &queue,
+            deadlines.run_deadline(),
+            Some(Duration::from_millis(10)),
+        );
+        assert_eq!(res, Err(-EAGAIN));
+    }
+
+    #[test]
+    fn block_drops_samples_once_stopped() {
+        let queue = new_queue(1, OverflowPolicy::Block);
+        queue.push(vec![1]);
+        queue.stopped.store(true, Ordering::Relaxed);
+        queue.push(vec![2]);
+        assert_eq!(queued(&queue), vec![vec![1]]);
+        assert_eq!(queue.state.lock().unwrap().stats.dropped_samples, 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::ptr::{null, null_mut};
+
+use libbpf_rs::libbpf_sys::{bpf_prog_test_run_opts, bpf_test_run_opts};
+use log::debug;
+
+use crate::{
+    ensure_c_str, ensure_enough_memory, ensure_program_mut_by_caller,
+    func::{EINVAL, ENOENT},
+    state::CallerType,
+    utils::CallerUtils,
+};
+
+use super::{BpfObjectType, WasmPointer, WasmString};
+
+/// The options passed by the guest to `wasm_bpf_prog_test_run`.
+/// Null pointers are allowed for buffers which are not used.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WasmTestRunOpts {
+    pub data_in: WasmPointer,
+    pub data_size_in: u32,
+    pub data_out: WasmPointer,
+    // Size of `data_out` on input, and the size of output data on return
+    pub data_size_out: u32,
+    pub ctx_in: WasmPointer,
+    pub ctx_size_in: u32,
+    pub ctx_out: WasmPointer,
+    // Size of `ctx_out` on input, and the size of output context on return
+    pub ctx_size_out: u32,
+    pub repeat: u32,
+    pub flags: u32,
+    pub cpu: u32,
+    // Filled on return
+    pub retval: u32,
+    // Filled on return, average duration of one run in nanoseconds
+    pub duration: u32,
+}
+
+/// Run the program named `name` in the kernel through BPF_PROG_TEST_RUN
+pub fn wasm_bpf_prog_test_run(
+    mut caller: CallerType,
+    program: BpfObjectType,
+    name: WasmString,
+    opts: WasmPointer,
+) -> i32 {
+    debug!("prog test run");
+    let prog_name = ensure_c_str!(caller, name);
+    let object = ensure_program_mut_by_caller!(caller, program);
+    let prog_fd = match object.get_object().prog(&prog_name) {
+        Some(v) => v.fd(),
+        None => {
+            debug!("No program named `{}` found", prog_name);
+            return -ENOENT;
+        }
+    };
+    let mut args = match caller.read_wasm_struct::<WasmTestRunOpts>(opts as usize) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Invalid pointer for opts: {}", err);
+            return -EINVAL;
+        }
+    };
+    for (pointer, size) in [
+        (args.data_in, args.data_size_in),
+        (args.data_out, args.data_size_out),
+        (args.ctx_in, args.ctx_size_in),
+        (args.ctx_out, args.ctx_size_out),
+    ] {
+        if pointer != 0 && size != 0 {
+            ensure_enough_memory!(caller, pointer, size, -EINVAL);
+        }
+    }
+    let mut run_opts = unsafe {
+        bpf_test_run_opts {
+            sz: std::mem::size_of::<bpf_test_run_opts>() as _,
+            data_in: if args.data_in == 0 {
+                null()
+            } else {
+                caller.raw_pointer_at_unchecked(args.data_in as usize) as *const _
+            },
+            data_size_in: args.data_size_in,
+            data_out: if args.data_out == 0 {
+                null_mut()
+            } else {
+                caller.raw_pointer_at_unchecked(args.data_out as usize) as *mut _
+            },
+            data_size_out: args.data_size_out,
+            ctx_in: if args.ctx_in == 0 {
+                null()
+            } else {
+                caller.raw_pointer_at_unchecked(args.ctx_in as usize) as *const _
+            },
+            ctx_size_in: args.ctx_size_in,
+            ctx_out: if args.ctx_out == 0 {
+                null_mut()
+            } else {
+                caller.raw_pointer_at_unchecked(args.ctx_out as usize) as *mut _
+            },
+            ctx_size_out: args.ctx_size_out,
+            repeat: args.repeat as _,
+            flags: args.flags,
+            cpu: args.cpu,
+            ..Default::default()
+        }
+    };
+    let ret_val = unsafe { bpf_prog_test_run_opts(prog_fd, &mut run_opts) };
+    if ret_val != 0 {
+        debug!("prog test run failed with {}", ret_val);
+        return ret_val;
+    }
+    args.data_size_out = run_opts.data_size_out;
+    args.ctx_size_out = run_opts.ctx_size_out;
+    args.retval = run_opts.retval;
+    args.duration = run_opts.duration;
+    if let Err(err) = caller.write_wasm_struct(opts as usize, &args) {
+        debug!("Invalid pointer for opts: {}", err);
+        return -EINVAL;
+    }
+    return 0;
+}
@@ -0,0 +1,388 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::c_void,
+    ptr::null_mut,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use libbpf_rs::libbpf_sys::{
+    BPF_MAP_TYPE_USER_RINGBUF, BPF_RINGBUF_BUSY_BIT, BPF_RINGBUF_DISCARD_BIT, BPF_RINGBUF_HDR_SZ,
+};
+use log::debug;
+
+use crate::{
+    ensure_enough_memory,
+    func::{E2BIG, EINVAL, ENOENT, ENOSPC},
+    state::{query_map_info, CallerType},
+    utils::CallerUtils,
+};
+
+use super::WasmPointer;
+
+/// The producer side of a `BPF_MAP_TYPE_USER_RINGBUF` map, which follows the
+/// protocol used by `user_ring_buffer__*` of libbpf.
+pub struct UserRingBuffer {
+    consumer_pos: *mut c_void,
+    producer_pos: *mut c_void,
+    data: *mut u8,
+    mask: u64,
+    page_size: usize,
+    // Reserved but not yet submitted samples, handle => reservation
+    reserved: HashMap<i32, Reservation>,
+    // Handle of the next reservation, handles aren't reused until they wrap
+    next_handle: i32,
+}
+
+/// A sample reserved on the ring, which the guest refers to by a handle
+#[derive(Clone, Copy, Debug)]
+struct Reservation {
+    // Offset of the header of the sample in `data`
+    header: u32,
+    // Offset of the sample in `data`, after its header. Wraps to 0 if the header
+    // takes the last bytes of the ring.
+    offset: u32,
+    size: u32,
+}
+
+impl UserRingBuffer {
+    /// Map the rings of `map_fd`, errors are returned as negative error codes
+    pub fn new(map_fd: i32) -> Result<Self, i32> {
+        let info = query_map_info(map_fd)?;
+        if info.type_ != BPF_MAP_TYPE_USER_RINGBUF {
+            debug!(
+                "Map {} is not a user ring buffer, type={}",
+                map_fd, info.type_
+            );
+            return Err(-EINVAL);
+        }
+        // The mask of offsets needs a power of 2, which also keeps offsets within u32
+        if !info.max_entries.is_power_of_two() {
+            debug!(
+                "Size of user ring buffer {} is not a power of 2: {}",
+                map_fd, info.max_entries
+            );
+            return Err(-EINVAL);
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // The consumer page is read-only for user space
+        let consumer_pos = unsafe {
+            libc::mmap(
+                null_mut(),
+                page_size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                map_fd,
+                0,
+            )
+        };
+        if consumer_pos == libc::MAP_FAILED {
+            return Err(-std::io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(EINVAL));
+        }
+        // The data pages are mapped twice by the kernel, so samples wrapping
+        // around the end of the buffer are still contiguous
+        let producer_pos = unsafe {
+            libc::mmap(
+                null_mut(),
+                page_size + 2 * info.max_entries as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                map_fd,
+                page_size as _,
+            )
+        };
+        if producer_pos == libc::MAP_FAILED {
+            let err = -std::io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(EINVAL);
+            unsafe { libc::munmap(consumer_pos, page_size) };
+            return Err(err);
+        }
+        Ok(Self {
+            consumer_pos,
+            producer_pos,
+            data: unsafe { (producer_pos as *mut u8).add(page_size) },
+            mask: info.max_entries as u64 - 1,
+            page_size,
+            reserved: HashMap::new(),
+            next_handle: 0,
+        })
+    }
+    /// Returns an unused handle, handles stay non-negative as they are returned to the guest
+    fn new_handle(&mut self) -> i32 {
+        loop {
+            let handle = self.next_handle;
+            self.next_handle = handle.checked_add(1).unwrap_or(0);
+            if !self.reserved.contains_key(&handle) {
+                return handle;
+            }
+        }
+    }
+    /// Reserve a sample of `size` bytes, returns the handle of the sample or a
+    /// negative error code
+    pub fn reserve(&mut self, size: u32) -> Result<i32, i32> {
+        if size & (BPF_RINGBUF_BUSY_BIT | BPF_RINGBUF_DISCARD_BIT) != 0 {
+            return Err(-E2BIG);
+        }
+        let consumer_pos = unsafe { &*(self.consumer_pos as *const AtomicU64) };
+        let producer_pos = unsafe { &*(self.producer_pos as *const AtomicU64) };
+        let cons_pos = consumer_pos.load(Ordering::Acquire);
+        let prod_pos = producer_pos.load(Ordering::Acquire);
+        let max_size = self.mask + 1;
+        let avail_size = max_size - (prod_pos - cons_pos);
+        // Round up the total size to a multiple of 8
+        let total_size = (size as u64 + BPF_RINGBUF_HDR_SZ as u64).next_multiple_of(8);
+        if total_size > max_size {
+            return Err(-E2BIG);
+        }
+        if avail_size < total_size {
+            return Err(-ENOSPC);
+        }
+        let header = (prod_pos & self.mask) as u32;
+        unsafe {
+            let hdr = self.data.add(header as usize) as *mut u32;
+            hdr.write(size | BPF_RINGBUF_BUSY_BIT);
+            hdr.add(1).write(0);
+        }
+        producer_pos.store(prod_pos + total_size, Ordering::Release);
+        let offset = ((prod_pos + BPF_RINGBUF_HDR_SZ as u64) & self.mask) as u32;
+        let handle = self.new_handle();
+        self.reserved.insert(
+            handle,
+            Reservation {
+                header,
+                offset,
+                size,
+            },
+        );
+        Ok(handle)
+    }
+    /// Returns the writable memory of a reserved sample
+    pub fn sample_mut(&mut self, handle: i32) -> Option<&mut [u8]> {
+        let reservation = *self.reserved.get(&handle)?;
+        Some(unsafe {
+            std::slice::from_raw_parts_mut(
+                self.data.add(reservation.offset as usize),
+                reservation.size as usize,
+            )
+        })
+    }
+    /// Submit or discard a reserved sample, errors are returned as negative error codes
+    pub fn commit(&mut self, handle: i32, discard: bool) -> Result<(), i32> {
+        let header = match self.reserved.remove(&handle) {
+            Some(v) => v.header,
+            None => return Err(-ENOENT),
+        };
+        let hdr = unsafe { &*(self.data.add(header as usize) as *const AtomicU32) };
+        let mut new_len = hdr.load(Ordering::Relaxed) & !BPF_RINGBUF_BUSY_BIT;
+        if discard {
+            new_len |= BPF_RINGBUF_DISCARD_BIT;
+        }
+        hdr.swap(new_len, Ordering::AcqRel);
+        Ok(())
+    }
+}
+
+impl Drop for UserRingBuffer {
+    fn drop(&mut self) {
+        // Samples left reserved would block the kernel side, so discard them
+        for handle in self.reserved.keys().copied().collect::<Vec<_>>() {
+            let _ = self.commit(handle, true);
+        }
+        unsafe {
+            libc::munmap(self.consumer_pos, self.page_size);
+            libc::munmap(
+                self.producer_pos,
+                self.page_size + 2 * (self.mask as usize + 1),
+            );
+        }
+    }
+}
+
+/// Reserve a sample of `size` bytes on the user ring buffer map `fd`.
+/// Returns a non-negative handle of the sample, or a negative error code.
+pub fn wasm_bpf_user_ringbuf_reserve(mut caller: CallerType, fd: i32, size: u32) -> i32 {
+    debug!("user ringbuf reserve");
+    let state = caller.data_mut();
+    if !state.is_known_map_fd(fd) {
+        debug!("No map with fd `{}` found", fd);
+        return -ENOENT;
+    }
+    let buffer = match state.user_ringbufs.entry(fd) {
+        Entry::Occupied(v) => v.into_mut(),
+        Entry::Vacant(v) => match UserRingBuffer::new(fd) {
+            Ok(buffer) => v.insert(buffer),
+            Err(err) => {
+                debug!("Failed to open user ring buffer {}: {}", fd, err);
+                return err;
+            }
+        },
+    };
+    match buffer.reserve(size) {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Failed to reserve {} bytes: {}", size, err);
+            err
+        }
+    }
+}
+
+/// Copy `size` bytes from `data` into the reserved sample, starting at `offset` of the sample
+pub fn wasm_bpf_user_ringbuf_write(
+    mut caller: CallerType,
+    fd: i32,
+    sample: i32,
+    offset: u32,
+    data: WasmPointer,
+    size: u32,
+) -> i32 {
+    debug!("user ringbuf write");
+    if size == 0 {
+        return 0;
+    }
+    ensure_enough_memory!(caller, data, size, -EINVAL);
+    let memory = caller.get_memory().expect("Expected exported memory!");
+    let (memory, state) = memory.data_and_store_mut(&mut caller);
+    let buffer = match state.user_ringbufs.get_mut(&fd) {
+        Some(v) => v,
+        None => {
+            debug!("No user ring buffer with fd `{}` opened", fd);
+            return -ENOENT;
+        }
+    };
+    let dst = match buffer.sample_mut(sample) {
+        Some(v) => v,
+        None => {
+            debug!("No reserved sample {}", sample);
+            return -ENOENT;
+        }
+    };
+    let end = offset as usize + size as usize;
+    if end > dst.len() {
+        debug!(
+            "Writing out of the sample: offset={}, size={}, sample size={}",
+            offset,
+            size,
+            dst.len()
+        );
+        return -EINVAL;
+    }
+    dst[offset as usize..end]
+        .copy_from_slice(&memory[data as usize..data as usize + size as usize]);
+    return 0;
+}
+
+fn user_ringbuf_commit(mut caller: CallerType, fd: i32, sample: i32, discard: bool) -> i32 {
+    let buffer = match caller.data_mut().user_ringbufs.get_mut(&fd) {
+        Some(v) => v,
+        None => {
+            debug!("No user ring buffer with fd `{}` opened", fd);
+            return -ENOENT;
+        }
+    };
+    match buffer.commit(sample, discard) {
+        Ok(_) => 0,
+        Err(err) => {
+            debug!("Failed to commit sample {}: {}", sample, err);
+            err
+        }
+    }
+}
+
+pub fn wasm_bpf_user_ringbuf_submit(caller: CallerType, fd: i32, sample: i32) -> i32 {
+    debug!("user ringbuf submit");
+    user_ringbuf_commit(caller, fd, sample, false)
+}
+
+pub fn wasm_bpf_user_ringbuf_discard(caller: CallerType, fd: i32, sample: i32) -> i32 {
+    debug!("user ringbuf discard");
+    user_ringbuf_commit(caller, fd, sample, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ring of `size` bytes in memory of the test, positioned at `pos`. The data is
+    /// twice as large like the double mapping of the kernel.
+    struct TestRing {
+        ring: Option<UserRingBuffer>,
+        _positions: Box<[AtomicU64; 2]>,
+        data: Vec<u64>,
+    }
+
+    impl TestRing {
+        fn new(size: usize, pos: u64) -> Self {
+            let mut positions = Box::new([AtomicU64::new(pos), AtomicU64::new(pos)]);
+            let mut data = vec![0u64; 2 * size / 8];
+            let ring = UserRingBuffer {
+                consumer_pos: &mut positions[0] as *mut AtomicU64 as *mut c_void,
+                producer_pos: &mut positions[1] as *mut AtomicU64 as *mut c_void,
+                data: data.as_mut_ptr() as *mut u8,
+                mask: size as u64 - 1,
+                page_size: 0,
+                reserved: HashMap::new(),
+                next_handle: 0,
+            };
+            Self {
+                ring: Some(ring),
+                _positions: positions,
+                data,
+            }
+        }
+        fn ring(&mut self) -> &mut UserRingBuffer {
+            self.ring.as_mut().unwrap()
+        }
+        fn header(&self, offset: usize) -> u32 {
+            unsafe {
+                (self.data.as_ptr() as *const u8)
+                    .add(offset)
+                    .cast::<u32>()
+                    .read()
+            }
+        }
+    }
+
+    impl Drop for TestRing {
+        fn drop(&mut self) {
+            // The memory isn't mapped, so it must not be unmapped
+            std::mem::forget(self.ring.take());
+        }
+    }
+
+    #[test]
+    fn samples_are_committed_through_their_header() {
+        let mut ring = TestRing::new(64, 0);
+        let first = ring.ring().reserve(4).unwrap();
+        let second = ring.ring().reserve(8).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(ring.header(0), 4 | BPF_RINGBUF_BUSY_BIT);
+        assert_eq!(ring.header(16), 8 | BPF_RINGBUF_BUSY_BIT);
+        ring.ring().commit(second, true).unwrap();
+        ring.ring().commit(first, false).unwrap();
+        assert_eq!(ring.header(0), 4);
+        assert_eq!(ring.header(16), 8 | BPF_RINGBUF_DISCARD_BIT);
+        assert_eq!(ring.ring().commit(first, false), Err(-ENOENT));
+    }
+
+    #[test]
+    fn sample_after_a_header_at_the_end_of_the_ring_wraps() {
+        // The header takes the last 8 bytes, the sample starts at the beginning
+        let mut ring = TestRing::new(64, 56);
+        let handle = ring.ring().reserve(8).unwrap();
+        assert_eq!(ring.header(56), 8 | BPF_RINGBUF_BUSY_BIT);
+        ring.ring().sample_mut(handle).unwrap().fill(0xaa);
+        assert_eq!(ring.data[0], u64::from_ne_bytes([0xaa; 8]));
+        ring.ring().commit(handle, false).unwrap();
+        assert_eq!(ring.header(56), 8);
+    }
+
+    #[test]
+    fn samples_must_fit_in_the_free_space() {
+        let mut ring = TestRing::new(64, 0);
+        assert_eq!(ring.ring().reserve(64), Err(-E2BIG));
+        ring.ring().reserve(40).unwrap();
+        assert_eq!(ring.ring().reserve(16), Err(-ENOSPC));
+    }
+}
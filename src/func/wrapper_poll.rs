@@ -5,7 +5,10 @@ use crate::{
     state::{CallerType, PollWrapper},
 };
 
-use super::{poll::wasm_bpf_buffer_poll, BpfObjectType, WasmPointer};
+use super::{
+    poll::{buffer_poll, legacy_poll_error},
+    BpfObjectType, WasmPointer,
+};
 
 pub fn bpf_buffer_poll_wrapper(
     mut caller: CallerType,
@@ -22,6 +25,7 @@ pub fn bpf_buffer_poll_wrapper(
         }
         PollWrapper::Enabled {
             ref callback_function_name,
+            ..
         } => callback_function_name.clone(),
     };
     if let Some(export) = caller.get_export(&callback_func_name) {
@@ -46,5 +50,19 @@ pub fn bpf_buffer_poll_wrapper(
         error!("Callback export named {} not found", callback_func_name);
         return EINVAL;
     }
-    wasm_bpf_buffer_poll(caller, program, fd, 0, ctx, data, max_size, timeout_ms)
+    // Samples and lost samples of the map are passed to the callback exports
+    buffer_poll(
+        caller,
+        program,
+        fd,
+        0,
+        ctx,
+        data,
+        max_size,
+        Some(timeout_ms),
+        false,
+        false,
+        true,
+    )
+    .unwrap_or_else(legacy_poll_error)
 }
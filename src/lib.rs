@@ -0,0 +1,596 @@
+// Host functions keep the explicit `return` style and mirror the C ABI of wasm-bpf
+#![allow(clippy::needless_return, clippy::too_many_arguments)]
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use deadline::EpochTicker;
+use log::{debug, error, info};
+use shutdown::{shutdown_signal, ShutdownSignalHandlers};
+use state::{AppState, PollWrapper};
+use wasmtime::{Engine, Linker, Module, Store, StoreLimitsBuilder};
+use wasmtime_wasi::{ambient_authority, Dir, I32Exit, WasiCtx, WasiCtxBuilder};
+
+use crate::func::{
+    attach::wasm_attach_bpf_program,
+    buffer_file::wasm_bpf_buffer_open_fd,
+    close::wasm_close_bpf_object,
+    fd_by_name::wasm_bpf_map_fd_by_name,
+    iter::{wasm_bpf_iter_close, wasm_bpf_iter_create, wasm_bpf_iter_read},
+    load::wasm_load_bpf_object,
+    map_create::wasm_bpf_map_create,
+    map_in_map::{
+        wasm_bpf_map_close, wasm_bpf_map_create_inner, wasm_bpf_map_insert_inner,
+        wasm_bpf_map_lookup_inner,
+    },
+    map_info::wasm_bpf_map_info,
+    map_operate::wasm_bpf_map_operate,
+    object_info::{
+        wasm_bpf_object_map_count, wasm_bpf_object_map_info, wasm_bpf_object_prog_count,
+        wasm_bpf_object_prog_info,
+    },
+    poll::{
+        wasm_bpf_buffer_consume, wasm_bpf_buffer_poll, wasm_bpf_buffer_poll_all,
+        wasm_bpf_buffer_poll_batch, wasm_bpf_buffer_poll_with_cpu, wasm_bpf_buffer_ready,
+        wasm_bpf_buffer_register, wasm_bpf_buffer_set_flags, wasm_bpf_buffer_set_lost_callback,
+        wasm_bpf_buffer_stats, wasm_bpf_set_perf_buffer_options,
+    },
+    prog_stats::wasm_bpf_prog_stats,
+    queue::{
+        wasm_bpf_buffer_queue_pop, wasm_bpf_buffer_queue_start, wasm_bpf_buffer_queue_stats,
+        wasm_bpf_buffer_queue_stop,
+    },
+    test_run::wasm_bpf_prog_test_run,
+    user_ringbuf::{
+        wasm_bpf_user_ringbuf_discard, wasm_bpf_user_ringbuf_reserve, wasm_bpf_user_ringbuf_submit,
+        wasm_bpf_user_ringbuf_write,
+    },
+    wrapper_poll,
+};
+
+pub use crate::deadline::{CpuLimits, DeadlineExceeded};
+pub use crate::func::poll::{PerfBufferOptions, PERF_BUFFER_PAGES};
+pub use crate::state::BpfResourceLimits;
+pub use crate::stdio::{OutputConfig, Rotation, StdinConfig};
+
+pub const MAIN_MODULE_NAME: &str = "main";
+pub const POLL_WRAPPER_FUNCTION_NAME: &str = "wasm_bpf_buffer_poll";
+mod deadline;
+mod func;
+mod shutdown;
+mod state;
+mod stdio;
+mod utils;
+
+/// Options of the runtime, shared by the command line and embedders
+#[derive(Clone, Debug)]
+pub struct RunnerConfig {
+    pub wrapper_module_name: String,
+    pub callback_export_name: String,
+    pub lost_callback_export_name: String,
+    pub batch_callback_export_name: String,
+    // Export used to allocate memory for samples in the guest
+    pub allocator_export_name: String,
+    // Enable BPF run time statistics while the module is running
+    pub enable_stats: bool,
+    pub perf_buffer_options: PerfBufferOptions,
+    // Interrupt the guest on SIGINT and SIGTERM, only used by `run_wasm_bpf_module`
+    pub handle_signals: bool,
+    // Export called after the guest is interrupted by a signal
+    pub shutdown_export_name: Option<String>,
+    // Environment variables of the guest, as (key, value)
+    pub envs: Vec<(String, String)>,
+    // Pass the environment variables of the runtime to the guest as well
+    pub inherit_env: bool,
+    // Host directories preopened for the guest, as (guest path, host path)
+    pub preopened_dirs: Vec<(String, PathBuf)>,
+    pub stdin: StdinConfig,
+    pub stdout: OutputConfig,
+    pub stderr: OutputConfig,
+    // Limits of the linear memory in bytes and of the table elements of the guest
+    pub max_memory_size: Option<usize>,
+    pub max_table_elements: Option<u32>,
+    pub resource_limits: BpfResourceLimits,
+    pub cpu_limits: CpuLimits,
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        Self {
+            wrapper_module_name: String::from("callback-wrapper"),
+            callback_export_name: String::from("go-callback"),
+            lost_callback_export_name: String::from("go-lost-callback"),
+            batch_callback_export_name: String::from("go-batch-callback"),
+            allocator_export_name: String::from("malloc"),
+            enable_stats: false,
+            perf_buffer_options: Default::default(),
+            handle_signals: false,
+            shutdown_export_name: None,
+            envs: vec![],
+            inherit_env: false,
+            preopened_dirs: vec![],
+            stdin: Default::default(),
+            stdout: Default::default(),
+            stderr: Default::default(),
+            max_memory_size: None,
+            max_table_elements: None,
+            resource_limits: Default::default(),
+            cpu_limits: Default::default(),
+        }
+    }
+}
+
+/// Build the WASI context of the guest with `$builder`, the `WasiCtxBuilder` of
+/// wasmtime-wasi or the one of its tokio flavor, which have the same methods
+macro_rules! build_wasi_ctx {
+    ($builder:ty, $args:expr, $config:expr) => {{
+        let (args, config): (&[String], &RunnerConfig) = ($args, $config);
+        let mut builder = <$builder>::new();
+        builder = match config.stdin.open()? {
+            Some(file) => builder.stdin(file),
+            None => builder.inherit_stdin(),
+        };
+        builder = match config.stdout.open()? {
+            Some(file) => builder.stdout(file),
+            None => builder.inherit_stdout(),
+        };
+        builder = match config.stderr.open()? {
+            Some(file) => builder.stderr(file),
+            None => builder.inherit_stderr(),
+        };
+        builder = builder
+            .args(args)
+            .with_context(|| anyhow!("Failed to build Wasi Context"))?
+            .envs(&guest_envs(config))
+            .with_context(|| anyhow!("Failed to set environment variables of the guest"))?;
+        for (guest_path, host_path) in config.preopened_dirs.iter() {
+            let dir = Dir::open_ambient_dir(host_path, ambient_authority())
+                .with_context(|| anyhow!("Failed to open directory `{}`", host_path.display()))?;
+            builder = builder.preopened_dir(dir, guest_path).with_context(|| {
+                anyhow!(
+                    "Failed to preopen `{}` as `{}`",
+                    host_path.display(),
+                    guest_path
+                )
+            })?;
+        }
+        Ok(builder.build())
+    }};
+}
+
+fn wasi_ctx(args: &[String], config: &RunnerConfig) -> anyhow::Result<WasiCtx> {
+    build_wasi_ctx!(WasiCtxBuilder, args, config)
+}
+
+/// WASI context whose blocking calls, such as `poll_oneoff` and reads of stdin,
+/// don't block the tokio runtime
+#[cfg(feature = "async")]
+fn wasi_ctx_async(args: &[String], config: &RunnerConfig) -> anyhow::Result<WasiCtx> {
+    build_wasi_ctx!(wasmtime_wasi::tokio::WasiCtxBuilder, args, config)
+}
+
+fn new_store(
+    engine: &Engine,
+    wasi: WasiCtx,
+    config: &RunnerConfig,
+) -> anyhow::Result<Store<AppState>> {
+    let mut store = Store::new(engine, AppState::new(wasi));
+    config
+        .perf_buffer_options
+        .validate()
+        .map_err(|e| anyhow!(e))?;
+    store.data_mut().perf_buffer_options = config.perf_buffer_options;
+    store.data_mut().allocator_export_name = config.allocator_export_name.clone();
+    store.data_mut().resource_limits = config.resource_limits;
+    store.data_mut().deadlines.limits = config.cpu_limits;
+    if let Some(fuel) = config.cpu_limits.max_fuel {
+        store.add_fuel(fuel)?;
+    }
+    let mut store_limits = StoreLimitsBuilder::new();
+    if let Some(limit) = config.max_memory_size {
+        store_limits = store_limits.memory_size(limit);
+    }
+    if let Some(limit) = config.max_table_elements {
+        store_limits = store_limits.table_elements(limit);
+    }
+    store.data_mut().store_limits = store_limits.build();
+    store.limiter(|s| &mut s.store_limits);
+    if config.enable_stats {
+        store
+            .data_mut()
+            .enable_bpf_stats()
+            .with_context(|| anyhow!("Failed to enable BPF stats"))?;
+    }
+    store.data_mut().poll_wrapper = PollWrapper::Enabled {
+        callback_function_name: config.callback_export_name.clone(),
+        lost_callback_function_name: config.lost_callback_export_name.clone(),
+        batch_callback_function_name: config.batch_callback_export_name.clone(),
+    };
+    Ok(store)
+}
+
+/// Options of the engine, `epoch_interruption` is also enabled if timeouts are set
+fn engine_config(config: &RunnerConfig, epoch_interruption: bool) -> wasmtime::Config {
+    let mut engine_config = wasmtime::Config::new();
+    engine_config
+        .epoch_interruption(epoch_interruption || config.cpu_limits.uses_epoch())
+        .consume_fuel(config.cpu_limits.uses_fuel());
+    engine_config
+}
+
+/// Check the timeouts and shutdown requests whenever the epoch is incremented
+fn enable_epoch_deadline(store: &mut Store<AppState>) {
+    store.epoch_deadline_callback(|state| state.deadlines.check());
+    store.set_epoch_deadline(1);
+}
+
+/// The explicit environment variables of the guest override the inherited ones
+fn guest_envs(config: &RunnerConfig) -> Vec<(String, String)> {
+    let mut envs: Vec<(String, String)> = if config.inherit_env {
+        std::env::vars().collect()
+    } else {
+        vec![]
+    };
+    for (key, value) in config.envs.iter() {
+        envs.retain(|(k, _)| k != key);
+        envs.push((key.clone(), value.clone()));
+    }
+    envs
+}
+
+/// Register the host functions which don't call back into the guest, WASI is
+/// registered by the runners
+fn add_host_functions(linker: &mut Linker<AppState>) -> anyhow::Result<()> {
+    add_bind_function!(linker, wasm_load_bpf_object)?;
+    add_bind_function!(linker, wasm_close_bpf_object)?;
+    add_bind_function!(linker, wasm_attach_bpf_program)?;
+    add_bind_function!(linker, wasm_bpf_set_perf_buffer_options)?;
+    add_bind_function!(linker, wasm_bpf_map_fd_by_name)?;
+    add_bind_function!(linker, wasm_bpf_map_operate)?;
+    add_bind_function!(linker, wasm_bpf_map_create)?;
+    add_bind_function!(linker, wasm_bpf_map_create_inner)?;
+    add_bind_function!(linker, wasm_bpf_map_insert_inner)?;
+    add_bind_function!(linker, wasm_bpf_map_lookup_inner)?;
+    add_bind_function!(linker, wasm_bpf_map_close)?;
+    add_bind_function!(linker, wasm_bpf_map_info)?;
+    add_bind_function!(linker, wasm_bpf_object_prog_count)?;
+    add_bind_function!(linker, wasm_bpf_object_prog_info)?;
+    add_bind_function!(linker, wasm_bpf_object_map_count)?;
+    add_bind_function!(linker, wasm_bpf_object_map_info)?;
+    add_bind_function!(linker, wasm_bpf_prog_test_run)?;
+    add_bind_function!(linker, wasm_bpf_prog_stats)?;
+    add_bind_function!(linker, wasm_bpf_iter_create)?;
+    add_bind_function!(linker, wasm_bpf_iter_read)?;
+    add_bind_function!(linker, wasm_bpf_iter_close)?;
+    add_bind_function!(linker, wasm_bpf_user_ringbuf_reserve)?;
+    add_bind_function!(linker, wasm_bpf_user_ringbuf_write)?;
+    add_bind_function!(linker, wasm_bpf_user_ringbuf_submit)?;
+    add_bind_function!(linker, wasm_bpf_user_ringbuf_discard)?;
+    Ok(())
+}
+
+/// Host functions of `run_wasm_bpf_module` which the async runner doesn't provide,
+/// since they wait for samples on the calling thread or call back the guest from
+/// libbpf. Guests importing them are rejected before they start.
+#[cfg(feature = "async")]
+const SYNC_ONLY_HOST_FUNCTIONS: &[&str] = &[
+    "wasm_bpf_buffer_consume",
+    "wasm_bpf_buffer_register",
+    "wasm_bpf_buffer_poll_all",
+    "wasm_bpf_buffer_ready",
+    "wasm_bpf_buffer_queue_start",
+    "wasm_bpf_buffer_queue_pop",
+    "wasm_bpf_buffer_queue_stats",
+    "wasm_bpf_buffer_queue_stop",
+    "wasm_bpf_buffer_open_fd",
+];
+
+/// Register the host functions which may block the calling thread while waiting
+/// for samples, which the async runner can't provide
+fn add_blocking_host_functions(linker: &mut Linker<AppState>) -> anyhow::Result<()> {
+    add_bind_function!(linker, wasm_bpf_buffer_queue_start)?;
+    add_bind_function!(linker, wasm_bpf_buffer_queue_pop)?;
+    add_bind_function!(linker, wasm_bpf_buffer_queue_stats)?;
+    add_bind_function!(linker, wasm_bpf_buffer_queue_stop)?;
+    // Reading a buffer file waits in libbpf unless it's set non-blocking
+    add_bind_function!(linker, wasm_bpf_buffer_open_fd)?;
+    Ok(())
+}
+
+/// Run the `_start` function of a wasm module, `args` are the arguments of the
+/// guest. Polling buffers blocks the calling thread. Everything created by the guest
+/// is released in order when it returns, or when it's interrupted by a signal.
+/// Returns the exit status of the guest, which is 0 unless it called `proc_exit`.
+/// Traps are returned as errors, and runs stopped by the `CpuLimits` as
+/// `DeadlineExceeded`.
+pub fn run_wasm_bpf_module(
+    module_binary: &[u8],
+    args: &[String],
+    config: RunnerConfig,
+) -> anyhow::Result<i32> {
+    let engine = Engine::new(&engine_config(&config, config.handle_signals))?;
+    let mut linker = Linker::new(&engine);
+    let mut store = new_store(&engine, wasi_ctx(args, &config)?, &config)?;
+    if config.handle_signals || config.cpu_limits.uses_epoch() {
+        enable_epoch_deadline(&mut store);
+    }
+    let main_module = Module::new(&engine, module_binary)
+        .with_context(|| anyhow!("Failed to read wasm module file"))?;
+
+    wasmtime_wasi::add_to_linker(&mut linker, |s: &mut AppState| &mut s.wasi)
+        .with_context(|| anyhow!("Failed to add wasmtime_wasi to linker"))?;
+    add_host_functions(&mut linker)?;
+    add_blocking_host_functions(&mut linker)?;
+    add_bind_function!(linker, wasm_bpf_buffer_poll)?;
+    add_bind_function!(linker, wasm_bpf_buffer_poll_with_cpu)?;
+    add_bind_function!(linker, wasm_bpf_buffer_poll_batch)?;
+    add_bind_function!(linker, wasm_bpf_buffer_consume)?;
+    add_bind_function!(linker, wasm_bpf_buffer_register)?;
+    add_bind_function!(linker, wasm_bpf_buffer_poll_all)?;
+    add_bind_function!(linker, wasm_bpf_buffer_ready)?;
+    add_bind_function!(linker, wasm_bpf_buffer_set_lost_callback)?;
+    add_bind_function!(linker, wasm_bpf_buffer_set_flags)?;
+    add_bind_function!(linker, wasm_bpf_buffer_stats)?;
+
+    add_bind_function_with_module_and_name!(
+        linker,
+        &config.wrapper_module_name,
+        wrapper_poll::bpf_buffer_poll_wrapper,
+        POLL_WRAPPER_FUNCTION_NAME
+    )?;
+    // linker.
+    linker
+        .module(&mut store, MAIN_MODULE_NAME, &main_module)
+        .with_context(|| anyhow!("Failed to link main module"))?;
+
+    let start = linker
+        .get(&mut store, MAIN_MODULE_NAME, "_start")
+        .with_context(|| anyhow!("Failed to get _start function"))?
+        .into_func()
+        .with_context(|| anyhow!("Failed to cast to func"))?
+        .typed::<(), ()>(&mut store)?;
+    let signal_handlers = if config.handle_signals {
+        Some(ShutdownSignalHandlers::install(&engine)?)
+    } else {
+        None
+    };
+    let ticker = config
+        .cpu_limits
+        .uses_epoch()
+        .then(|| EpochTicker::start(&engine));
+    store.data_mut().deadlines.start_run();
+    let result = exit_status(start.call(&mut store, ()));
+    let mut result = store.data().deadlines.map_result(result);
+    // The epoch is only incremented by the signal handlers from now on
+    drop(ticker);
+    if let Some(sig) = shutdown_signal() {
+        info!("Received signal {}, shutting down", sig);
+        // The guest was interrupted, don't report it as a failure
+        result = Ok(0);
+        if let Some(name) = &config.shutdown_export_name {
+            result = Ok(call_shutdown_export(&linker, &mut store, name));
+        }
+    }
+    drop(signal_handlers);
+    store.data_mut().detach_all();
+    return result;
+}
+
+/// Map the result of the guest to its exit status, `proc_exit` is reported by WASI as an error
+fn exit_status(result: anyhow::Result<()>) -> anyhow::Result<i32> {
+    match result {
+        Ok(()) => Ok(0),
+        Err(err) => match err.downcast_ref::<I32Exit>() {
+            Some(exit) => Ok(exit.0),
+            None => Err(err),
+        },
+    }
+}
+
+/// Call the export `name` of the interrupted guest, so it can flush its state.
+/// Returns the exit status set by the export.
+fn call_shutdown_export(linker: &Linker<AppState>, store: &mut Store<AppState>, name: &str) -> i32 {
+    // Another signal interrupts the export again
+    store.set_epoch_deadline(1);
+    let func = match linker
+        .get(&mut *store, MAIN_MODULE_NAME, name)
+        .and_then(|v| v.into_func())
+    {
+        Some(v) => v,
+        None => {
+            debug!("Shutdown export named {} not found", name);
+            return 0;
+        }
+    };
+    let result = func
+        .typed::<(), ()>(&mut *store)
+        .and_then(|v| v.call(&mut *store, ()));
+    match exit_status(result) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to call the shutdown export {}: {}", name, e);
+            0
+        }
+    }
+}
+
+/// Async version of `run_wasm_bpf_module`, to be run on a multi-threaded tokio runtime
+/// with IO and time enabled. The guest runs on a wasmtime fiber, and waiting for
+/// samples of `wasm_bpf_buffer_poll` yields to the runtime instead of blocking the
+/// thread, like the WASI calls of the guest such as `poll_oneoff`.
+///
+/// Only `wasm_bpf_buffer_poll`, `wasm_bpf_buffer_poll_with_cpu`, `wasm_bpf_buffer_poll_batch`
+/// and the poll wrapper are provided to poll buffers, the other ones are tied to the
+/// blocking libbpf poll. `wasm_bpf_buffer_set_flags` applies to them like it does to
+/// the sync versions.
+/// Queues and buffer file descriptors aren't provided either, since popping a queue
+/// and reading a buffer file wait for samples on the thread of the runtime. Guests
+/// importing any of them fail to start with an error naming the import.
+#[cfg(feature = "async")]
+pub async fn run_wasm_bpf_module_async(
+    module_binary: &[u8],
+    args: &[String],
+    config: RunnerConfig,
+) -> anyhow::Result<i32> {
+    use crate::func::async_poll::{
+        bpf_buffer_poll_wrapper_async, wasm_bpf_buffer_poll_async,
+        wasm_bpf_buffer_poll_batch_async, wasm_bpf_buffer_set_flags_async,
+        wasm_bpf_buffer_set_lost_callback_async,
+    };
+    use crate::func::{BpfObjectType, WasmPointer};
+    use wasmtime::Caller;
+
+    let mut engine_config = engine_config(&config, false);
+    engine_config.async_support(true);
+    let engine = Engine::new(&engine_config)?;
+    let mut linker = Linker::new(&engine);
+    let mut store = new_store(&engine, wasi_ctx_async(args, &config)?, &config)?;
+    if config.cpu_limits.uses_epoch() {
+        enable_epoch_deadline(&mut store);
+    }
+    let main_module = Module::new(&engine, module_binary)
+        .with_context(|| anyhow!("Failed to read wasm module file"))?;
+    if let Some(import) = main_module
+        .imports()
+        .find(|v| v.module() == "env" && SYNC_ONLY_HOST_FUNCTIONS.contains(&v.name()))
+    {
+        return Err(anyhow!(
+            "Host function `{}` is not supported by the async runner, run the module with `run_wasm_bpf_module`",
+            import.name()
+        ));
+    }
+
+    wasmtime_wasi::tokio::add_to_linker(&mut linker, |s: &mut AppState| &mut s.wasi)
+        .with_context(|| anyhow!("Failed to add wasmtime_wasi to linker"))?;
+    add_host_functions(&mut linker)?;
+    add_bind_function!(linker, wasm_bpf_buffer_stats)?;
+    add_bind_function_with_module_and_name!(
+        linker,
+        "env",
+        wasm_bpf_buffer_set_lost_callback_async,
+        "wasm_bpf_buffer_set_lost_callback"
+    )?;
+    add_bind_function_with_module_and_name!(
+        linker,
+        "env",
+        wasm_bpf_buffer_set_flags_async,
+        "wasm_bpf_buffer_set_flags"
+    )?;
+    for (name, with_cpu) in [
+        ("wasm_bpf_buffer_poll", false),
+        ("wasm_bpf_buffer_poll_with_cpu", true),
+    ] {
+        linker
+            .func_wrap7_async(
+                "env",
+                name,
+                move |caller: Caller<'_, AppState>,
+                      program: BpfObjectType,
+                      fd: i32,
+                      sample_func: WasmPointer,
+                      ctx: WasmPointer,
+                      data: WasmPointer,
+                      max_size: i32,
+                      timeout_ms: i32| {
+                    Box::new(wasm_bpf_buffer_poll_async(
+                        caller,
+                        program,
+                        fd,
+                        sample_func,
+                        ctx,
+                        data,
+                        max_size,
+                        timeout_ms,
+                        with_cpu,
+                    ))
+                },
+            )
+            .with_context(|| anyhow!("Failed to register host function `{}`", name))?;
+    }
+    linker
+        .func_wrap7_async(
+            "env",
+            "wasm_bpf_buffer_poll_batch",
+            |caller: Caller<'_, AppState>,
+             program: BpfObjectType,
+             fd: i32,
+             batch_func: WasmPointer,
+             ctx: WasmPointer,
+             data: WasmPointer,
+             max_size: i32,
+             timeout_ms: i32| {
+                Box::new(wasm_bpf_buffer_poll_batch_async(
+                    caller, program, fd, batch_func, ctx, data, max_size, timeout_ms,
+                ))
+            },
+        )
+        .with_context(|| {
+            anyhow!("Failed to register host function `wasm_bpf_buffer_poll_batch`")
+        })?;
+    linker
+        .func_wrap6_async(
+            &config.wrapper_module_name,
+            POLL_WRAPPER_FUNCTION_NAME,
+            |caller: Caller<'_, AppState>,
+             program: BpfObjectType,
+             fd: i32,
+             ctx: WasmPointer,
+             data: WasmPointer,
+             max_size: i32,
+             timeout_ms: i32| {
+                Box::new(bpf_buffer_poll_wrapper_async(
+                    caller, program, fd, ctx, data, max_size, timeout_ms,
+                ))
+            },
+        )
+        .with_context(|| anyhow!("Failed to register the async poll wrapper"))?;
+    linker
+        .module_async(&mut store, MAIN_MODULE_NAME, &main_module)
+        .await
+        .with_context(|| anyhow!("Failed to link main module"))?;
+
+    let start = linker
+        .get(&mut store, MAIN_MODULE_NAME, "_start")
+        .with_context(|| anyhow!("Failed to get _start function"))?
+        .into_func()
+        .with_context(|| anyhow!("Failed to cast to func"))?
+        .typed::<(), ()>(&mut store)?;
+    let ticker = config
+        .cpu_limits
+        .uses_epoch()
+        .then(|| EpochTicker::start(&engine));
+    store.data_mut().deadlines.start_run();
+    let result = exit_status(start.call_async(&mut store, ()).await);
+    let result = store.data().deadlines.map_result(result);
+    drop(ticker);
+    store.data_mut().detach_all();
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Trap;
+
+    #[test]
+    fn exit_status_of_a_returned_guest_is_zero() {
+        assert_eq!(exit_status(Ok(())).unwrap(), 0);
+    }
+
+    #[test]
+    fn proc_exit_is_reported_as_the_exit_status() {
+        assert_eq!(exit_status(Err(I32Exit(3).into())).unwrap(), 3);
+        // Still found below the context added by wasmtime
+        let err =
+            anyhow::Error::from(I32Exit(7)).context("error while executing at wasm backtrace");
+        assert_eq!(exit_status(Err(err)).unwrap(), 7);
+    }
+
+    #[test]
+    fn other_errors_are_kept() {
+        let err = exit_status(Err(Trap::UnreachableCodeReached.into())).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Trap>(),
+            Some(&Trap::UnreachableCodeReached)
+        );
+    }
+}
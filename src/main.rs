@@ -1,23 +1,22 @@
+use std::{path::PathBuf, time::Duration};
+
 use anyhow::{anyhow, Context};
 use clap::Parser;
 use flexi_logger::Logger;
 use log_format::my_log_format;
-use state::{AppState, PollWrapper};
-use wasmtime::{Engine, Linker, Module, Store};
-use wasmtime_wasi::WasiCtxBuilder;
-
-use crate::func::{
-    attach::wasm_attach_bpf_program, close::wasm_close_bpf_object,
-    fd_by_name::wasm_bpf_map_fd_by_name, load::wasm_load_bpf_object,
-    map_operate::wasm_bpf_map_operate, poll::wasm_bpf_buffer_poll, wrapper_poll,
+use wasm_bpf_rs::{
+    run_wasm_bpf_module, BpfResourceLimits, CpuLimits, DeadlineExceeded, OutputConfig,
+    PerfBufferOptions, Rotation, RunnerConfig, StdinConfig, PERF_BUFFER_PAGES,
 };
+use wasmtime::Trap;
 
-pub const MAIN_MODULE_NAME: &str = "main";
-pub const POLL_WRAPPER_FUNCTION_NAME: &str = "wasm_bpf_buffer_poll";
-mod func;
 mod log_format;
-mod state;
-mod utils;
+
+// Exit status when the guest traps, 128 + SIGABRT like wasmtime. Other errors of
+// the runtime exit with 1.
+const TRAP_EXIT_CODE: i32 = 134;
+// Exit status when the guest exceeds its fuel or timeouts, like timeout(1)
+const DEADLINE_EXIT_CODE: i32 = 124;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -27,8 +26,15 @@ mod utils;
     long_about = "A WebAssembly runtime for eBPF user-space programs."
 )]
 struct CommandArgs {
-    #[arg(help = "The WebAssembly Module file to run")]
-    wasm_module_file: String,
+    // Flags after the module file are passed to the guest, so both are parsed together
+    #[arg(
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        value_name = "MODULE_FILE [ARGS]",
+        help = "The WebAssembly Module file to run, followed by the arguments of the guest"
+    )]
+    guest_args: Vec<String>,
     #[arg(long, help = "Display more logs")]
     verbose: bool,
     // #[arg(short = 'w', long, help = "Enable polyfill wrapper")]
@@ -37,55 +43,300 @@ struct CommandArgs {
     wrapper_module_name: String,
     #[arg(short = 'c', long, help = "Callback export name", default_value_t = String::from("go-callback"))]
     callback_export_name: String,
+    #[arg(long, help = "Lost samples callback export name", default_value_t = String::from("go-lost-callback"))]
+    lost_callback_export_name: String,
+    #[arg(long, help = "Batch callback export name", default_value_t = String::from("go-batch-callback"))]
+    batch_callback_export_name: String,
+    #[arg(long, help = "Export used to allocate memory for samples in the guest", default_value_t = String::from("malloc"))]
+    allocator_export_name: String,
+    #[arg(
+        long,
+        help = "Enable BPF run time statistics (BPF_ENABLE_STATS) while the module is running"
+    )]
+    enable_stats: bool,
+    #[arg(long, help = "Number of pages of each CPU for perf buffers, must be a power of 2", default_value_t = PERF_BUFFER_PAGES as usize)]
+    perf_buffer_pages: usize,
+    #[arg(long, help = "Wake up perf buffer pollers every N samples", default_value_t = 0)]
+    perf_wakeup_events: u32,
+    #[arg(long, help = "Wake up perf buffer pollers once N bytes are available", default_value_t = 0)]
+    perf_wakeup_watermark: u32,
+    #[arg(
+        long,
+        help = "Export of the guest called when it's interrupted by SIGINT or SIGTERM"
+    )]
+    shutdown_export_name: Option<String>,
+    #[arg(
+        long = "env",
+        value_name = "KEY=VALUE",
+        value_parser = parse_env,
+        help = "Set an environment variable of the guest"
+    )]
+    envs: Vec<(String, String)>,
+    #[arg(
+        long,
+        help = "Pass the environment variables of the runtime to the guest"
+    )]
+    inherit_env: bool,
+    #[arg(
+        long = "dir",
+        value_name = "DIR",
+        help = "Preopen a host directory at the same path for the guest"
+    )]
+    dirs: Vec<String>,
+    #[arg(
+        long = "mapdir",
+        value_name = "GUEST::HOST",
+        value_parser = parse_mapdir,
+        help = "Preopen a host directory at another path for the guest"
+    )]
+    mapdirs: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read the standard input of the guest from a file"
+    )]
+    stdin: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Append the standard output of the guest to a file"
+    )]
+    stdout: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Append the standard error of the guest to a file"
+    )]
+    stderr: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Rotate the output file of --stdout once it reaches this size"
+    )]
+    stdout_rotate_size: Option<u64>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Rotate the output file of --stderr once it reaches this size"
+    )]
+    stderr_rotate_size: Option<u64>,
+    #[arg(
+        long,
+        help = "Number of rotated files to keep for each output",
+        default_value_t = 5
+    )]
+    rotate_keep: usize,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Maximum size of the linear memory of the guest"
+    )]
+    max_memory: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of elements of each table of the guest"
+    )]
+    max_table_elements: Option<u32>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of BPF objects loaded at once"
+    )]
+    max_objects: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of BPF links attached at once"
+    )]
+    max_links: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of BPF buffers consumed at once"
+    )]
+    max_buffers: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of files opened for the guest at once"
+    )]
+    max_opened_files: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of maps created or opened by the runtime for the guest at once"
+    )]
+    max_host_maps: Option<usize>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Fuel of the whole run, consumed by executing wasm instructions"
+    )]
+    fuel: Option<u64>,
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Maximum time in milliseconds the guest runs for, including waits for samples"
+    )]
+    timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Maximum time in milliseconds of each call of a callback"
+    )]
+    callback_timeout: Option<u64>,
+}
+
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", value)),
+    }
+}
+
+fn parse_mapdir(value: &str) -> Result<(String, String), String> {
+    match value.split_once("::") {
+        Some((guest, host)) if !guest.is_empty() && !host.is_empty() => {
+            Ok((guest.to_string(), host.to_string()))
+        }
+        _ => Err(format!("expected GUEST::HOST, got `{}`", value)),
+    }
+}
+
+fn output_config(
+    path: Option<PathBuf>,
+    rotate_size: Option<u64>,
+    rotate_keep: usize,
+) -> OutputConfig {
+    match path {
+        Some(path) => OutputConfig::File {
+            path,
+            rotation: rotate_size.map(|max_size| Rotation {
+                max_size,
+                max_files: rotate_keep,
+            }),
+        },
+        None => OutputConfig::Inherit,
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = CommandArgs::parse();
+    let mut args = CommandArgs::parse();
+    // `MODULE_FILE -- ARGS` is kept as a separator, since clap only consumes a `--`
+    // before the module file
+    if args.guest_args.get(1).map(String::as_str) == Some("--") {
+        args.guest_args.remove(1);
+    }
 
     Logger::try_with_str(if args.verbose { "debug" } else { "info" })?
         .format(my_log_format)
         .start()?;
 
-    let engine = Engine::default();
-    let mut linker = Linker::new(&engine);
-    wasmtime_wasi::add_to_linker(&mut linker, |s: &mut AppState| &mut s.wasi)
-        .with_context(|| anyhow!("Failed to add wasmtime_wasi to linker"))?;
-    let wasi = WasiCtxBuilder::new()
-        .inherit_stdio()
-        .inherit_args()
-        .with_context(|| anyhow!("Failed to build Wasi Context"))?
-        .build();
-    let mut store = Store::new(&engine, AppState::new(wasi));
-    let main_module = Module::from_file(&engine, args.wasm_module_file)
+    let module_binary = std::fs::read(&args.guest_args[0])
         .with_context(|| anyhow!("Failed to read wasm module file"))?;
-
-    add_bind_function!(linker, wasm_load_bpf_object)?;
-    add_bind_function!(linker, wasm_close_bpf_object)?;
-    add_bind_function!(linker, wasm_attach_bpf_program)?;
-    add_bind_function!(linker, wasm_bpf_buffer_poll)?;
-    add_bind_function!(linker, wasm_bpf_map_fd_by_name)?;
-    add_bind_function!(linker, wasm_bpf_map_operate)?;
-
-    add_bind_function_with_module_and_name!(
-        linker,
-        &args.wrapper_module_name,
-        wrapper_poll::bpf_buffer_poll_wrapper,
-        POLL_WRAPPER_FUNCTION_NAME
-    )?;
-    store.data_mut().poll_wrapper = PollWrapper::Enabled {
-        callback_function_name: args.callback_export_name,
+    let config = RunnerConfig {
+        wrapper_module_name: args.wrapper_module_name,
+        callback_export_name: args.callback_export_name,
+        lost_callback_export_name: args.lost_callback_export_name,
+        batch_callback_export_name: args.batch_callback_export_name,
+        allocator_export_name: args.allocator_export_name,
+        enable_stats: args.enable_stats,
+        perf_buffer_options: PerfBufferOptions {
+            page_cnt: args.perf_buffer_pages,
+            wakeup_events: args.perf_wakeup_events,
+            wakeup_watermark: args.perf_wakeup_watermark,
+        },
+        handle_signals: true,
+        shutdown_export_name: args.shutdown_export_name,
+        envs: args.envs,
+        inherit_env: args.inherit_env,
+        preopened_dirs: args
+            .dirs
+            .iter()
+            .map(|v| (v.clone(), v.into()))
+            .chain(
+                args.mapdirs
+                    .iter()
+                    .map(|(guest, host)| (guest.clone(), host.into())),
+            )
+            .collect(),
+        stdin: args.stdin.map_or(StdinConfig::Inherit, StdinConfig::File),
+        stdout: output_config(args.stdout, args.stdout_rotate_size, args.rotate_keep),
+        stderr: output_config(args.stderr, args.stderr_rotate_size, args.rotate_keep),
+        max_memory_size: args.max_memory,
+        max_table_elements: args.max_table_elements,
+        resource_limits: BpfResourceLimits {
+            max_objects: args.max_objects,
+            max_links: args.max_links,
+            max_buffers: args.max_buffers,
+            max_opened_files: args.max_opened_files,
+            max_host_maps: args.max_host_maps,
+        },
+        cpu_limits: CpuLimits {
+            max_fuel: args.fuel,
+            timeout: args.timeout.map(Duration::from_millis),
+            callback_timeout: args.callback_timeout.map(Duration::from_millis),
+        },
     };
-    // linker.
-    linker
-        .module(&mut store, MAIN_MODULE_NAME, &main_module)
-        .with_context(|| anyhow!("Failed to link main module"))?;
-
-    linker
-        .get(&mut store, MAIN_MODULE_NAME, "_start")
-        .with_context(|| anyhow!("Failed to get _start function"))?
-        .into_func()
-        .with_context(|| anyhow!("Failed to cast to func"))?
-        .typed::<(), ()>(&mut store)?
-        .call(&mut store, ())?;
-    return Ok(());
+    // The runner releases everything created by the guest before returning,
+    // so it's fine to exit without running destructors
+    match run_wasm_bpf_module(
+        &module_binary,
+        // The guest sees the module file as its program name
+        &args.guest_args,
+        config,
+    ) {
+        Ok(code) => std::process::exit(code),
+        Err(err) if err.is::<DeadlineExceeded>() => {
+            eprintln!("Error: {:?}", err);
+            std::process::exit(DEADLINE_EXIT_CODE);
+        }
+        Err(err) if err.is::<Trap>() => {
+            eprintln!("Error: {:?}", err);
+            std::process::exit(TRAP_EXIT_CODE);
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn env_is_split_at_the_first_equal_sign() {
+        assert_eq!(parse_env("KEY=value"), Ok(pair("KEY", "value")));
+        assert_eq!(parse_env("KEY="), Ok(pair("KEY", "")));
+        assert_eq!(parse_env("KEY=a=b"), Ok(pair("KEY", "a=b")));
+    }
+
+    #[test]
+    fn env_needs_a_key_and_an_equal_sign() {
+        assert!(parse_env("KEY").is_err());
+        assert!(parse_env("=value").is_err());
+        assert!(parse_env("").is_err());
+    }
+
+    #[test]
+    fn mapdir_is_split_at_the_first_separator() {
+        assert_eq!(
+            parse_mapdir("/data::/tmp/data"),
+            Ok(pair("/data", "/tmp/data"))
+        );
+        assert_eq!(parse_mapdir(".::/"), Ok(pair(".", "/")));
+        assert_eq!(parse_mapdir("a::b::c"), Ok(pair("a", "b::c")));
+    }
+
+    #[test]
+    fn mapdir_needs_both_directories() {
+        assert!(parse_mapdir("/data").is_err());
+        assert!(parse_mapdir("/data:/tmp").is_err());
+        assert!(parse_mapdir("::/tmp").is_err());
+        assert!(parse_mapdir("/data::").is_err());
+    }
 }
@@ -0,0 +1,100 @@
+use std::{
+    mem::MaybeUninit,
+    ptr::null_mut,
+    sync::atomic::{AtomicI32, AtomicPtr, AtomicU64, Ordering},
+};
+
+use anyhow::bail;
+use wasmtime::Engine;
+
+const SHUTDOWN_SIGNALS: [i32; 2] = [libc::SIGINT, libc::SIGTERM];
+
+// The last shutdown signal received, zero if none
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+// The thread running the guest, which receives the signals so blocking host calls
+// return with EINTR
+static GUEST_THREAD: AtomicU64 = AtomicU64::new(0);
+// The engine of the running guest, whose epoch is incremented to interrupt it
+static GUEST_ENGINE: AtomicPtr<Engine> = AtomicPtr::new(null_mut());
+
+/// Returns the shutdown signal received while the guest is running, if any
+pub fn shutdown_signal() -> Option<i32> {
+    match SHUTDOWN_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Whether host functions should give up waiting and return to the guest
+pub fn is_shutdown_requested() -> bool {
+    shutdown_signal().is_some()
+}
+
+extern "C" fn handle_shutdown_signal(sig: i32) {
+    SHUTDOWN_SIGNAL.store(sig, Ordering::SeqCst);
+    let guest_thread = GUEST_THREAD.load(Ordering::SeqCst);
+    if unsafe { libc::pthread_self() } as u64 != guest_thread {
+        // Interrupt the guest thread as well, which may be blocked in a poll
+        unsafe { libc::pthread_kill(guest_thread as libc::pthread_t, sig) };
+        return;
+    }
+    let engine = GUEST_ENGINE.load(Ordering::SeqCst);
+    if !engine.is_null() {
+        // Only an atomic increment, so it's signal-safe
+        unsafe { &*engine }.increment_epoch();
+    }
+}
+
+/// Handlers of SIGINT and SIGTERM installed while a guest is running on the current
+/// thread. A signal interrupts the guest through the epoch of `engine`, which must
+/// have epoch interruption enabled. The previous handlers are restored on drop.
+pub struct ShutdownSignalHandlers {
+    old_actions: Vec<(i32, libc::sigaction)>,
+}
+
+impl ShutdownSignalHandlers {
+    pub fn install(engine: &Engine) -> anyhow::Result<Self> {
+        let engine = Box::into_raw(Box::new(engine.clone()));
+        let old_engine = GUEST_ENGINE.swap(engine, Ordering::SeqCst);
+        if !old_engine.is_null() {
+            GUEST_ENGINE.store(old_engine, Ordering::SeqCst);
+            drop(unsafe { Box::from_raw(engine) });
+            bail!("Signal handlers are already installed by another guest");
+        }
+        SHUTDOWN_SIGNAL.store(0, Ordering::SeqCst);
+        GUEST_THREAD.store(unsafe { libc::pthread_self() } as u64, Ordering::SeqCst);
+        let mut handlers = Self {
+            old_actions: vec![],
+        };
+        for sig in SHUTDOWN_SIGNALS {
+            let mut action: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+            action.sa_sigaction = handle_shutdown_signal as *const () as usize;
+            // No SA_RESTART, so blocking syscalls of the guest thread fail with EINTR
+            action.sa_flags = 0;
+            unsafe { libc::sigemptyset(&mut action.sa_mask) };
+            let mut old_action = unsafe { MaybeUninit::zeroed().assume_init() };
+            if unsafe { libc::sigaction(sig, &action, &mut old_action) } != 0 {
+                bail!(
+                    "Failed to install the handler of signal {}: {}",
+                    sig,
+                    std::io::Error::last_os_error()
+                );
+            }
+            handlers.old_actions.push((sig, old_action));
+        }
+        Ok(handlers)
+    }
+}
+
+impl Drop for ShutdownSignalHandlers {
+    fn drop(&mut self) {
+        for (sig, old_action) in self.old_actions.iter() {
+            unsafe { libc::sigaction(*sig, old_action, null_mut()) };
+        }
+        let engine = GUEST_ENGINE.swap(null_mut(), Ordering::SeqCst);
+        if !engine.is_null() {
+            drop(unsafe { Box::from_raw(engine) });
+        }
+        SHUTDOWN_SIGNAL.store(0, Ordering::SeqCst);
+    }
+}
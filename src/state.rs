@@ -1,19 +1,60 @@
-use std::{collections::HashMap, fs::File, ptr::null};
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fs::File,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr::null,
+};
 
 use libbpf_rs::{
-    libbpf_sys::{self, bpf_map, bpf_map__fd, bpf_object__next_map},
+    libbpf_sys::{
+        self, bpf_enable_stats, bpf_map, bpf_map__fd, bpf_map_info, bpf_obj_get_info_by_fd,
+        bpf_object__next_map, BPF_STATS_RUN_TIME,
+    },
     Link, Map, Object, Program,
 };
-use wasmtime::Caller;
+use log::{debug, warn};
+use wasmtime::{Caller, StoreLimits, StoreLimitsBuilder};
 use wasmtime_wasi::WasiCtx;
 
-use crate::func::poll::BpfBuffer;
+use crate::deadline::Deadlines;
+#[cfg(feature = "async")]
+use crate::func::async_poll::AsyncBuffer;
+use crate::func::{
+    buffer_file::BufferFiles,
+    iter::BpfIter,
+    poll::{BpfBuffer, BufferInnerType, PerfBufferOptions},
+    queue::SampleQueue,
+    user_ringbuf::UserRingBuffer,
+    BpfObjectType, EBUSY, EMFILE, ENOENT,
+};
 
 const FIRST_OBJECT_ID: u64 = 1;
 
 pub struct WrapperObject {
     pub object: Object,
-    pub buffer: Option<BpfBuffer>,
+    pub buffers: Vec<BpfBuffer>,
+    // Maps drained by host threads, indexed by the map fd
+    pub queues: HashMap<i32, SampleQueue>,
+    // Maps polled by the async runner, indexed by the map fd
+    #[cfg(feature = "async")]
+    pub async_buffers: HashMap<i32, AsyncBuffer>,
+    // Maps read through buffer files, which remove their map when they are closed
+    pub buffer_files: BufferFiles,
+    // Flags set by `wasm_bpf_buffer_set_flags` for maps without a buffer yet,
+    // applied when the buffer is opened
+    pub pending_buffer_flags: HashMap<i32, u32>,
+}
+
+impl Drop for WrapperObject {
+    fn drop(&mut self) {
+        // Stop consuming the maps before the object closes them
+        self.queues.clear();
+        self.buffers.clear();
+        #[cfg(feature = "async")]
+        self.async_buffers.clear();
+        self.buffer_files.close_all();
+    }
 }
 
 impl WrapperObject {
@@ -23,21 +64,143 @@ impl WrapperObject {
     pub fn get_object_mut(&mut self) -> &mut Object {
         &mut self.object
     }
+    pub fn get_buffer_by_fd_mut(&mut self, fd: i32) -> Option<&mut BpfBuffer> {
+        self.buffers.iter_mut().find(|v| v.contains_map(fd))
+    }
+    pub fn get_ring_buffer_mut(&mut self) -> Option<&mut BpfBuffer> {
+        self.buffers
+            .iter_mut()
+            .find(|v| matches!(v.inner, BufferInnerType::RingBuffer(_)))
+    }
+    /// Number of maps consumed by buffers, queues, buffer files or the async runner
+    pub fn consumed_map_count(&self) -> usize {
+        let count = self
+            .buffers
+            .iter()
+            .map(|v| v.host_ctx_boxes.len())
+            .sum::<usize>()
+            + self.queues.len()
+            + self.buffer_files.len();
+        #[cfg(feature = "async")]
+        let count = count + self.async_buffers.len();
+        count
+    }
+    /// Whether the map `fd` is consumed by a buffer, a queue, a buffer file or the async runner
+    pub fn is_map_consumed(&mut self, fd: i32) -> bool {
+        #[cfg(feature = "async")]
+        if self.async_buffers.contains_key(&fd) {
+            return true;
+        }
+        self.queues.contains_key(&fd)
+            || self.buffer_files.contains(fd)
+            || self.get_buffer_by_fd_mut(fd).is_some()
+    }
+    pub unsafe fn get_raw_object_ptr(&self) -> *mut libbpf_sys::bpf_object {
+        let ptr = self.get_object() as *const Object as *const MyObject;
+        (*ptr).ptr
+    }
+}
+/// A map which doesn't belong to any loaded object, such as an inner map of
+/// a map-in-map. The fd is owned by the runtime and closed on drop.
+pub struct HostMap {
+    pub fd: OwnedFd,
+    pub info: bpf_map_info,
+}
+
+impl HostMap {
+    pub fn new(fd: OwnedFd) -> Result<Self, i32> {
+        let info = query_map_info(fd.as_raw_fd())?;
+        Ok(Self { fd, info })
+    }
 }
+
+/// Query the kernel-side information of an arbitrary map fd
+pub fn query_map_info(fd: i32) -> Result<bpf_map_info, i32> {
+    let mut info = bpf_map_info::default();
+    let mut info_len = std::mem::size_of::<bpf_map_info>() as u32;
+    let ret =
+        unsafe { bpf_obj_get_info_by_fd(fd, &mut info as *mut _ as *mut c_void, &mut info_len) };
+    if ret != 0 {
+        return Err(ret);
+    }
+    Ok(info)
+}
+
 #[derive(Clone, Debug)]
 pub enum PollWrapper {
     Disabled,
-    Enabled { callback_function_name: String },
+    Enabled {
+        callback_function_name: String,
+        lost_callback_function_name: String,
+        batch_callback_function_name: String,
+    },
+}
+
+/// Limits of what a guest can create, None means unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BpfResourceLimits {
+    pub max_objects: Option<usize>,
+    // Attached programs, including iterators
+    pub max_links: Option<usize>,
+    // Maps consumed by buffers, queues or buffer file descriptors
+    pub max_buffers: Option<usize>,
+    pub max_opened_files: Option<usize>,
+    // Maps created by the runtime or opened from map-in-maps
+    pub max_host_maps: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BpfResource {
+    Object,
+    Link,
+    Buffer,
+    OpenedFile,
+    HostMap,
 }
 
 pub struct AppState {
     pub wasi: WasiCtx,
     pub next_object_id: u64,
     pub object_map: HashMap<u64, WrapperObject>,
+    pub host_maps: HashMap<i32, HostMap>,
     pub opened_files: Vec<File>,
     pub opened_links: Vec<Link>,
+    pub iterators: HashMap<i32, BpfIter>,
+    pub user_ringbufs: HashMap<i32, UserRingBuffer>,
     pub poll_wrapper: PollWrapper,
+    pub perf_buffer_options: PerfBufferOptions,
+    // BPF stats stay enabled as long as this fd is open
+    pub stats_fd: Option<OwnedFd>,
+    // Set to the code returned by the current poll once it's stopped: the non-zero
+    // value returned by a callback, -ECANCELED if the timeout of the run passed, or
+    // -EINVAL if a callback export is missing. Kept after the poll until the next one
+    pub poll_stop_code: Option<i32>,
+    // Number of polls running, objects closed by their callbacks are released once
+    // the outermost one returns
+    pub poll_depth: u32,
+    pub deferred_closes: Vec<BpfObjectType>,
+    // Export of the guest used to allocate memory for samples, see `BUFFER_FLAG_ALLOC_SAMPLES`
+    pub allocator_export_name: String,
+    pub resource_limits: BpfResourceLimits,
+    // Limits of the linear memories and tables of the guest
+    pub store_limits: StoreLimits,
+    // Fuel and timeouts of the guest and its callbacks
+    pub deadlines: Deadlines,
 }
+// SAFETY: the async runner moves the store between the worker threads of the runtime.
+// AppState is only kept from being Send by raw pointers: the libbpf objects, programs,
+// maps and links, the ring buffers and perf buffers, the pages mapped for user ring
+// buffers, and the pointer to the store kept by the contexts of the sample callbacks.
+// There is no Rc or RefCell state, and libbpf doesn't tie these to a thread, so moving
+// them is fine as long as two threads never use them at once:
+// - The store is polled by one task at a time, and the background threads of the
+//   queues, which consume buffers on their own, aren't provided by the async runner.
+// - The store pointer of the contexts is set by each sync poll and only used by the
+//   libbpf callbacks it runs. Sync host functions never yield, so the store can't
+//   move to another thread meanwhile.
+#[cfg(feature = "async")]
+unsafe impl Send for AppState {}
+
 #[allow(unused)]
 struct MyObject {
     pub ptr: *mut libbpf_sys::bpf_object,
@@ -50,10 +213,30 @@ impl AppState {
             wasi,
             next_object_id: FIRST_OBJECT_ID,
             object_map: Default::default(),
+            host_maps: Default::default(),
             opened_files: vec![],
             opened_links: vec![],
+            iterators: Default::default(),
+            user_ringbufs: Default::default(),
             poll_wrapper: PollWrapper::Disabled,
+            perf_buffer_options: Default::default(),
+            stats_fd: None,
+            poll_stop_code: None,
+            poll_depth: 0,
+            deferred_closes: vec![],
+            allocator_export_name: String::from("malloc"),
+            resource_limits: Default::default(),
+            store_limits: StoreLimitsBuilder::new().build(),
+            deadlines: Default::default(),
+        }
+    }
+    pub fn enable_bpf_stats(&mut self) -> std::io::Result<()> {
+        let fd = unsafe { bpf_enable_stats(BPF_STATS_RUN_TIME) };
+        if fd < 0 {
+            return Err(std::io::Error::from_raw_os_error(-fd));
         }
+        self.stats_fd = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        Ok(())
     }
     pub fn get_map_by_fd(&self, fd: i32) -> Option<&Map> {
         let mut map = None;
@@ -67,10 +250,33 @@ impl AppState {
         }
         return map;
     }
+    /// Lookup the key size and value size of a map, either owned by an object or by the runtime
+    pub fn get_map_key_value_size_by_fd(&self, fd: i32) -> Option<(usize, usize)> {
+        if let Some(map) = self.get_map_by_fd(fd) {
+            return Some((map.key_size() as usize, map.value_size() as usize));
+        }
+        self.host_maps
+            .get(&fd)
+            .map(|v| (v.info.key_size as usize, v.info.value_size as usize))
+    }
+    /// Take the ownership of `fd` and track it as a host map, returns the fd
+    pub fn register_host_map(&mut self, fd: i32) -> i32 {
+        let map = match HostMap::new(unsafe { OwnedFd::from_raw_fd(fd) }) {
+            Ok(v) => v,
+            Err(err) => {
+                log::debug!("Failed to query info of map {}: {}", fd, err);
+                return err;
+            }
+        };
+        self.host_maps.insert(fd, map);
+        return fd;
+    }
+    pub fn is_known_map_fd(&self, fd: i32) -> bool {
+        self.get_map_by_fd(fd).is_some() || self.host_maps.contains_key(&fd)
+    }
     pub unsafe fn get_map_ptr_by_fd(&self, fd: i32) -> Option<*const bpf_map> {
         for prog in self.object_map.values() {
-            let ptr = prog.get_object() as *const Object as *const MyObject;
-            let bpf_object_ptr = (*ptr).ptr;
+            let bpf_object_ptr = prog.get_raw_object_ptr();
             let mut pos = bpf_object__next_map(bpf_object_ptr, null());
             while !pos.is_null() {
                 if bpf_map__fd(pos) == fd {
@@ -81,6 +287,121 @@ impl AppState {
         }
         return None;
     }
+    /// Number of `resource` currently held by the guest
+    pub fn resource_count(&self, resource: BpfResource) -> usize {
+        match resource {
+            BpfResource::Object => self.object_map.len(),
+            BpfResource::Link => self.opened_links.len() + self.iterators.len(),
+            BpfResource::Buffer => self
+                .object_map
+                .values()
+                .map(|v| v.consumed_map_count())
+                .sum::<usize>(),
+            BpfResource::OpenedFile => self.opened_files.len() + self.iterators.len(),
+            BpfResource::HostMap => self.host_maps.len(),
+        }
+    }
+    /// Whether the guest may create another `resource`, callers report EMFILE if not
+    pub fn can_create(&self, resource: BpfResource) -> bool {
+        let limit = match resource {
+            BpfResource::Object => self.resource_limits.max_objects,
+            BpfResource::Link => self.resource_limits.max_links,
+            BpfResource::Buffer => self.resource_limits.max_buffers,
+            BpfResource::OpenedFile => self.resource_limits.max_opened_files,
+            BpfResource::HostMap => self.resource_limits.max_host_maps,
+        };
+        match limit {
+            Some(limit) if self.resource_count(resource) >= limit => {
+                warn!("Limit of {:?} reached: {}", resource, limit);
+                false
+            }
+            _ => true,
+        }
+    }
+    /// Check that another buffer, queue or buffer file may consume the map `fd` of
+    /// `program`: the map must belong to the object and not be consumed yet, within
+    /// the limit of buffers. Errors are returned as negative error codes.
+    pub fn check_new_consumer(&mut self, program: BpfObjectType, fd: i32) -> Result<(), i32> {
+        let object = match self.object_map.get(&program) {
+            Some(v) => v,
+            None => {
+                debug!("Invalid program: {}", program);
+                return Err(-1);
+            }
+        };
+        // Consumers are tracked by the object owning the map
+        if !object.get_object().maps_iter().any(|v| v.fd() == fd) {
+            debug!("Map fd {} doesn't belong to object {}", fd, program);
+            return Err(-ENOENT);
+        }
+        if !self.can_create(BpfResource::Buffer) {
+            return Err(-EMFILE);
+        }
+        let object = self.object_map.get_mut(&program).unwrap();
+        if object.is_map_consumed(fd) {
+            debug!("Map fd {} is already being consumed", fd);
+            return Err(-EBUSY);
+        }
+        Ok(())
+    }
+    /// Close the object `program`, or defer it until the running polls return since
+    /// they still use its buffers. Returns false if there's no such object.
+    pub fn close_object(&mut self, program: BpfObjectType) -> bool {
+        if !self.object_map.contains_key(&program) || self.deferred_closes.contains(&program) {
+            return false;
+        }
+        if self.poll_depth > 0 {
+            self.deferred_closes.push(program);
+        } else {
+            self.release_object(program);
+        }
+        true
+    }
+    fn release_object(&mut self, program: BpfObjectType) {
+        let object = match self.object_map.remove(&program) {
+            Some(v) => v,
+            None => return,
+        };
+        let map_fds = object
+            .get_object()
+            .maps_iter()
+            .map(|map| map.fd())
+            .collect::<Vec<_>>();
+        drop(object);
+        // Maps opened afterwards may reuse the fds of the object
+        self.user_ringbufs.retain(|fd, _| !map_fds.contains(fd));
+    }
+    /// Called before a poll delivers samples to the guest
+    pub fn enter_poll(&mut self) {
+        self.poll_depth += 1;
+    }
+    /// Called once a poll is done with the buffers, releases the objects closed in the
+    /// meantime if it's the outermost poll
+    pub fn leave_poll(&mut self) {
+        self.poll_depth -= 1;
+        if self.poll_depth == 0 {
+            for program in std::mem::take(&mut self.deferred_closes) {
+                self.release_object(program);
+            }
+        }
+    }
+    /// Release everything the guest created in order: links, buffers, objects,
+    /// maps and files
+    pub fn detach_all(&mut self) {
+        self.opened_links.clear();
+        self.iterators.clear();
+        for object in self.object_map.values_mut() {
+            object.queues.clear();
+            object.buffers.clear();
+            #[cfg(feature = "async")]
+            object.async_buffers.clear();
+        }
+        self.user_ringbufs.clear();
+        self.object_map.clear();
+        self.host_maps.clear();
+        self.opened_files.clear();
+        self.stats_fd = None;
+    }
 }
 
 pub type CallerType<'a> = Caller<'a, AppState>;
@@ -0,0 +1,281 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use wasi_common::{
+    pipe::{ReadPipe, WritePipe},
+    WasiFile,
+};
+
+/// Where the guest reads its standard input from
+#[derive(Clone, Debug, Default)]
+pub enum StdinConfig {
+    #[default]
+    Inherit,
+    // Reads return end of file
+    Null,
+    File(PathBuf),
+}
+
+/// Where the guest writes its standard output or standard error to
+#[derive(Clone, Debug, Default)]
+pub enum OutputConfig {
+    #[default]
+    Inherit,
+    // Writes are discarded
+    Null,
+    // Appended to the file, which is rotated if `rotation` is set
+    File {
+        path: PathBuf,
+        rotation: Option<Rotation>,
+    },
+}
+
+/// Size-based rotation of an output file. Once writing to `path` would exceed
+/// `max_size` bytes, it's renamed to `path.1`, `path.1` to `path.2` and so on, and
+/// only `max_files` of the renamed files are kept. Only regular files are rotated,
+/// so devices such as `/dev/null` are left alone.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+impl StdinConfig {
+    /// Open the file of the stream, None if it's inherited
+    pub(crate) fn open(&self) -> anyhow::Result<Option<Box<dyn WasiFile>>> {
+        Ok(match self {
+            StdinConfig::Inherit => None,
+            StdinConfig::Null => Some(Box::new(ReadPipe::new(std::io::empty()))),
+            StdinConfig::File(path) => {
+                let file = File::open(path)
+                    .with_context(|| anyhow!("Failed to open `{}`", path.display()))?;
+                Some(Box::new(ReadPipe::new(file)))
+            }
+        })
+    }
+}
+
+impl OutputConfig {
+    /// Open the file of the stream, None if it's inherited
+    pub(crate) fn open(&self) -> anyhow::Result<Option<Box<dyn WasiFile>>> {
+        Ok(match self {
+            OutputConfig::Inherit => None,
+            OutputConfig::Null => Some(Box::new(WritePipe::new(std::io::sink()))),
+            OutputConfig::File { path, rotation } => {
+                let file = RotatingFile::open(path, *rotation)
+                    .with_context(|| anyhow!("Failed to open `{}`", path.display()))?;
+                Some(Box::new(WritePipe::new(file)))
+            }
+        })
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: Option<Rotation>,
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    path.into()
+}
+
+/// Rename `from` to `to`, a missing `from` is fine
+fn rename_if_exists(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl RotatingFile {
+    fn open(path: &Path, rotation: Option<Rotation>) -> std::io::Result<Self> {
+        let file = open_append(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size: metadata.len(),
+            // Renaming a device and creating a regular file in its place would break it
+            rotation: rotation.filter(|_| metadata.is_file()),
+        })
+    }
+    fn rotate(&mut self, max_files: usize) -> std::io::Result<()> {
+        if max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated_path(&self.path, max_files));
+            for index in (1..max_files).rev() {
+                rename_if_exists(
+                    &rotated_path(&self.path, index),
+                    &rotated_path(&self.path, index + 1),
+                )?;
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(rotation) = self.rotation {
+            // A write larger than the limit goes into a file of its own
+            if self.size > 0 && self.size + buf.len() as u64 > rotation.max_size {
+                self.rotate(rotation.max_files)?;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of the test, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("wasm-bpf-rs-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read(path: &Path) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn rotation_renames_the_older_files() {
+        let dir = TempDir::new("rename");
+        let path = dir.0.join("out.log");
+        let rotation = Rotation {
+            max_size: 10,
+            max_files: 3,
+        };
+        let mut file = RotatingFile::open(&path, Some(rotation)).unwrap();
+        for data in ["aaaaaaaa", "bbbb", "cccccccc"] {
+            file.write_all(data.as_bytes()).unwrap();
+        }
+        assert_eq!(read(&path).as_deref(), Some("cccccccc"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("bbbb"));
+        assert_eq!(read(&rotated_path(&path, 2)).as_deref(), Some("aaaaaaaa"));
+        assert_eq!(read(&rotated_path(&path, 3)), None);
+    }
+
+    #[test]
+    fn rotation_keeps_max_files() {
+        let dir = TempDir::new("prune");
+        let path = dir.0.join("out.log");
+        let rotation = Rotation {
+            max_size: 4,
+            max_files: 2,
+        };
+        let mut file = RotatingFile::open(&path, Some(rotation)).unwrap();
+        for data in ["aaaa", "bbbb", "cccc", "dddd"] {
+            file.write_all(data.as_bytes()).unwrap();
+        }
+        assert_eq!(read(&path).as_deref(), Some("dddd"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("cccc"));
+        assert_eq!(read(&rotated_path(&path, 2)).as_deref(), Some("bbbb"));
+        assert_eq!(read(&rotated_path(&path, 3)), None);
+    }
+
+    #[test]
+    fn rotation_without_files_truncates() {
+        let dir = TempDir::new("truncate");
+        let path = dir.0.join("out.log");
+        let rotation = Rotation {
+            max_size: 4,
+            max_files: 0,
+        };
+        let mut file = RotatingFile::open(&path, Some(rotation)).unwrap();
+        for data in ["aaaa", "bbbb"] {
+            file.write_all(data.as_bytes()).unwrap();
+        }
+        assert_eq!(read(&path).as_deref(), Some("bbbb"));
+        assert_eq!(read(&rotated_path(&path, 1)), None);
+    }
+
+    #[test]
+    fn oversized_write_goes_into_its_own_file() {
+        let dir = TempDir::new("oversized");
+        let path = dir.0.join("out.log");
+        let rotation = Rotation {
+            max_size: 4,
+            max_files: 2,
+        };
+        let mut file = RotatingFile::open(&path, Some(rotation)).unwrap();
+        file.write_all(b"aa").unwrap();
+        file.write_all(b"bbbbbbbbbb").unwrap();
+        assert_eq!(read(&path).as_deref(), Some("bbbbbbbbbb"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("aa"));
+        file.write_all(b"c").unwrap();
+        assert_eq!(read(&path).as_deref(), Some("c"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("bbbbbbbbbb"));
+        assert_eq!(read(&rotated_path(&path, 2)).as_deref(), Some("aa"));
+    }
+
+    #[test]
+    fn rotation_leaves_devices_alone() {
+        let dir = TempDir::new("device");
+        // Goes through a link, so a regression renames the link instead of the device
+        let path = dir.0.join("null");
+        std::os::unix::fs::symlink("/dev/null", &path).unwrap();
+        let rotation = Rotation {
+            max_size: 4,
+            max_files: 2,
+        };
+        let mut file = RotatingFile::open(&path, Some(rotation)).unwrap();
+        for data in ["aaaa", "bbbb"] {
+            file.write_all(data.as_bytes()).unwrap();
+        }
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_symlink());
+        assert!(!rotated_path(&path, 1).exists());
+    }
+
+    #[test]
+    fn existing_content_counts_toward_the_limit() {
+        let dir = TempDir::new("existing");
+        let path = dir.0.join("out.log");
+        std::fs::write(&path, "aaa").unwrap();
+        let rotation = Rotation {
+            max_size: 4,
+            max_files: 1,
+        };
+        let mut file = RotatingFile::open(&path, Some(rotation)).unwrap();
+        file.write_all(b"bb").unwrap();
+        assert_eq!(read(&path).as_deref(), Some("bb"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("aaa"));
+    }
+}
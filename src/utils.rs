@@ -2,7 +2,7 @@ use std::ffi::CStr;
 
 use crate::{AppState, state::CallerType};
 use anyhow::{anyhow, bail, Context};
-use wasmtime::{Caller, Memory, Table, WasmParams, WasmResults};
+use wasmtime::{Caller, Func, Memory, Table, WasmParams, WasmResults};
 
 const INDIRECT_TABLE_NAME: &str = "__indirect_function_table";
 
@@ -10,13 +10,19 @@ pub trait CallerUtils {
     fn get_memory(&mut self) -> anyhow::Result<Memory>;
     fn get_indirect_call_table(&mut self) -> anyhow::Result<Table>;
     // Terminated zero won't be put in the returned Vec
+    #[allow(unused)]
     fn read_wasm_string(&mut self, offset: usize) -> anyhow::Result<Vec<u8>>;
     // Terminated zero won't be included
+    #[allow(unused)]
     fn read_wasm_string_slice(&mut self, offset: usize) -> anyhow::Result<&[u8]>;
     // Terminated zero will be included
     fn read_wasm_string_slice_include_zero(&mut self, offset: usize) -> anyhow::Result<&[u8]>;
     fn read_zero_terminated_str(&mut self, offset: usize) -> anyhow::Result<&str>;
     unsafe fn raw_pointer_at_unchecked(&mut self, offset: usize) -> *const u8;
+    // Read a plain `#[repr(C)]` struct from wasm memory
+    fn read_wasm_struct<T: Copy + Default>(&mut self, offset: usize) -> anyhow::Result<T>;
+    // Write the raw bytes of a plain `#[repr(C)]` struct into wasm memory
+    fn write_wasm_struct<T: Copy>(&mut self, offset: usize, value: &T) -> anyhow::Result<()>;
 }
 
 impl CallerUtils for Caller<'_, AppState> {
@@ -92,18 +98,53 @@ impl CallerUtils for Caller<'_, AppState> {
             .read_wasm_string_slice_include_zero(offset)
             .with_context(|| anyhow!("Failed to read byte slice"))?;
         let c_str = CStr::from_bytes_with_nul(data_slice).unwrap();
-        return Ok(c_str
+        return c_str
             .to_str()
-            .with_context(|| anyhow!("Failed to decode bytes into utf8 str"))?);
+            .with_context(|| anyhow!("Failed to decode bytes into utf8 str"));
     }
 
     unsafe fn raw_pointer_at_unchecked(&mut self, offset: usize) -> *const u8 {
         let memory = self.get_memory().expect("Expected memory exported");
         memory.data_ptr(self).add(offset)
     }
+
+    fn read_wasm_struct<T: Copy + Default>(&mut self, offset: usize) -> anyhow::Result<T> {
+        let memory = self.get_memory()?;
+        let mut value = T::default();
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut value as *mut T as *mut u8,
+                std::mem::size_of::<T>(),
+            )
+        };
+        memory.read(self, offset, bytes).with_context(|| {
+            anyhow!(
+                "Failed to read {} bytes at {}, may be memory index out of bound",
+                bytes.len(),
+                offset
+            )
+        })?;
+        return Ok(value);
+    }
+
+    fn write_wasm_struct<T: Copy>(&mut self, offset: usize, value: &T) -> anyhow::Result<()> {
+        let memory = self.get_memory()?;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+        };
+        memory.write(self, offset, bytes).with_context(|| {
+            anyhow!(
+                "Failed to write {} bytes at {}, may be memory index out of bound",
+                bytes.len(),
+                offset
+            )
+        })
+    }
 }
 
 pub trait FunctionQuickCall {
+    fn get_indirect_function(&mut self, index: u32) -> anyhow::Result<Func>;
+    #[allow(unused)]
     fn perform_indirect_call<Params: WasmParams, Return: WasmResults>(
         &mut self,
         index: u32,
@@ -112,14 +153,9 @@ pub trait FunctionQuickCall {
 }
 
 impl FunctionQuickCall for CallerType<'_> {
-    fn perform_indirect_call<Params: WasmParams, Return: WasmResults>(
-        &mut self,
-        index: u32,
-        params: Params,
-    ) -> anyhow::Result<Return> {
-        let table = self
-            .get_indirect_call_table()
-            .expect("Indirect call table expected!");
+    fn get_indirect_function(&mut self, index: u32) -> anyhow::Result<Func> {
+        // Called from libbpf callbacks, so a guest without a table must not panic
+        let table = self.get_indirect_call_table()?;
         let item = table
             .get(&mut *self, index)
             .with_context(|| anyhow!("No func with index {} found", index))?;
@@ -127,6 +163,14 @@ impl FunctionQuickCall for CallerType<'_> {
             .funcref()
             .with_context(|| anyhow!("Expect element with index {} to be a function", index))?
             .with_context(|| anyhow!("Invalid type, function expected"))?;
+        return Ok(*func);
+    }
+    fn perform_indirect_call<Params: WasmParams, Return: WasmResults>(
+        &mut self,
+        index: u32,
+        params: Params,
+    ) -> anyhow::Result<Return> {
+        let func = self.get_indirect_function(index)?;
         let ret_val = func
             .typed::<Params, Return>(&mut *self)
             .with_context(|| anyhow!("Invalid function type provides"))?